@@ -0,0 +1,49 @@
+//! Measures the overhead of scheduling and polling tasks, with futures that
+//! do no work other than waking themselves.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::future::poll_fn;
+use iou_http::runtime::{spawn, Runtime};
+use std::task::Poll;
+
+// Wakes itself `polls - 1` times before finishing, so it's polled `polls` times
+async fn yield_times(polls: usize) {
+    let mut remaining = polls;
+    poll_fn(move |cx| {
+        remaining -= 1;
+        if remaining == 0 {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+fn polls(c: &mut Criterion) {
+    // Tasks are spawned onto the runtime most recently created on this
+    // thread, so a single runtime is shared by every iteration
+    let mut runtime = Runtime::new();
+    let mut group = c.benchmark_group("executor");
+    group.bench_function("poll 1000 times", |b| {
+        b.iter(|| {
+            runtime.spawn(yield_times(1000));
+            runtime.run();
+        })
+    });
+    group.bench_function("spawn 1000 tasks", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                for _ in 0..1000 {
+                    spawn(yield_times(10));
+                }
+            });
+            runtime.run();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, polls);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+//! Measures the overhead of submitting operations and reaping their
+//! completions, with each of the ways the io-uring instance can be set up.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use iou_http::runtime::{spawn, RuntimeBuilder};
+use iou_http::syscall::Timeout;
+use iou_http::time::sleep;
+use std::time::Duration;
+
+fn modes() -> Vec<(&'static str, RuntimeBuilder)> {
+    let builder = RuntimeBuilder::new().entries(256);
+    vec![
+        ("default", builder.clone()),
+        ("sqpoll", builder.clone().sqpoll(Duration::from_millis(10))),
+        ("coop_taskrun", builder.clone().coop_taskrun()),
+        ("defer_taskrun", builder.defer_taskrun()),
+    ]
+}
+
+fn timeouts(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reactor");
+    for (mode, builder) in modes() {
+        // Tasks are spawned onto the runtime most recently created on this
+        // thread, so each runtime is only used by its own benchmark
+        let mut runtime = match builder.build() {
+            Ok(runtime) => runtime,
+            Err(err) => {
+                eprintln!("skipping {}: {}", mode, err);
+                continue;
+            }
+        };
+        group.bench_function(format!("1000 timeouts ({})", mode), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    for _ in 0..1000 {
+                        spawn(async {
+                            let _ = Timeout::submit(Duration::from_nanos(1)).await;
+                        });
+                    }
+                });
+                runtime.run();
+            })
+        });
+        // Sleeps share a single io-uring timeout, but can't be shorter than
+        // the timer wheel's resolution of 1ms
+        group.bench_function(format!("1000 sleeps ({})", mode), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    for _ in 0..1000 {
+                        spawn(sleep(Duration::from_millis(1)));
+                    }
+                });
+                runtime.run();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, timeouts);
+criterion_main!(benches);
@@ -0,0 +1,164 @@
+//! Bodies that are passed along a piece at a time, for handlers that forward
+//! them elsewhere rather than needing them whole.
+
+use crate::net::with_deadline;
+use crate::syscall::Recv;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use std::cell::Cell;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const READ_SIZE: usize = 16 * 1024;
+
+/// A body that is produced a piece at a time. An error ends the body early,
+/// which for a response means the connection is closed without finishing it.
+pub type BodyStream = LocalBoxStream<'static, io::Result<Vec<u8>>>;
+
+/// A `BodyStream` with all of `body` in a single piece.
+pub fn full(body: Vec<u8>) -> BodyStream {
+    if body.is_empty() {
+        return stream::empty().boxed_local();
+    }
+    stream::once(async move { Ok(body) }).boxed_local()
+}
+
+/// The body of a request that is read from the connection as it's consumed,
+/// passed to a `StreamingCall`.
+///
+/// Each read has to finish within the server's read timeout, and before the
+/// deadline set with `set_deadline` if there is one. The body can
+/// only be read until the response has been sent, and if it isn't read to
+/// the end, the connection is closed afterwards. Like `TcpStream::read`, a
+/// read should be driven to completion rather than dropped part way through.
+pub struct RequestBody {
+    content_length: usize,
+    // The part of the body that the server read along with the head
+    buffered: Vec<u8>,
+    source: Rc<BodySource>,
+    deadline: Option<Instant>,
+}
+
+// The connection a request body is read from, which is shared with the
+// server so that it knows whether the whole body was read
+pub(crate) struct BodySource {
+    fd: RawFd,
+    read_timeout: Option<Duration>,
+    remaining: Cell<usize>,
+    // Cleared once the server is done with the request, since the socket
+    // may then be read for the next request, or closed and its fd reused
+    open: Cell<bool>,
+}
+
+impl BodySource {
+    pub(crate) fn new(
+        fd: RawFd,
+        read_timeout: Option<Duration>,
+        remaining: usize,
+    ) -> Rc<BodySource> {
+        Rc::new(BodySource {
+            fd,
+            read_timeout,
+            remaining: Cell::new(remaining),
+            open: Cell::new(true),
+        })
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.remaining.get()
+    }
+
+    // Stops the body from being read any further, returning whether it was
+    // all read
+    pub(crate) fn close(&self) -> bool {
+        self.open.set(false);
+        self.remaining.get() == 0
+    }
+}
+
+impl RequestBody {
+    pub(crate) fn new(buffered: Vec<u8>, source: Rc<BodySource>) -> RequestBody {
+        RequestBody {
+            content_length: buffered.len() + source.remaining(),
+            buffered,
+            source,
+            deadline: None,
+        }
+    }
+
+    /// The length of the whole body, from the request's Content-Length.
+    pub fn content_length(&self) -> usize {
+        self.content_length
+    }
+
+    /// Makes reads fail with `TimedOut` once `deadline` has passed, for
+    /// example so that a client that stops sending the body can't hold up
+    /// whatever it's being forwarded to for longer than that.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    /// Reads the next piece of the body, returning None once it has all been
+    /// read.
+    pub async fn chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.buffered.is_empty() {
+            return Ok(Some(mem::take(&mut self.buffered)));
+        }
+        let source = &self.source;
+        let remaining = source.remaining.get();
+        if remaining == 0 {
+            return Ok(None);
+        }
+        if !source.open.get() {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "the response to the request has already been sent",
+            ));
+        }
+
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(ErrorKind::TimedOut.into());
+                }
+                Some(
+                    source
+                        .read_timeout
+                        .map_or(left, |timeout| timeout.min(left)),
+                )
+            }
+            None => source.read_timeout,
+        };
+
+        let mut buf = vec![0; remaining.min(READ_SIZE)];
+        let mut fd = source.fd;
+        let read = Recv::submit(&mut buf, &mut fd);
+        let received = match timeout {
+            Some(timeout) => with_deadline(source.fd, timeout, read)
+                .await
+                .unwrap_or_else(|| Err(ErrorKind::TimedOut.into()))?,
+            None => read.await?,
+        } as usize;
+        if received == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before the request body was complete",
+            ));
+        }
+        buf.truncate(received);
+        source.remaining.set(remaining - received);
+        Ok(Some(buf))
+    }
+
+    /// Turns the body into a stream of its pieces, for example to send it on
+    /// with `HttpClient::send_streaming`.
+    pub fn into_stream(self) -> BodyStream {
+        stream::try_unfold(self, |mut body| async move {
+            Ok(body.chunk().await?.map(|piece| (piece, body)))
+        })
+        .boxed_local()
+    }
+}
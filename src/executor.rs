@@ -1,87 +1,331 @@
-use tracing::trace;
+use crate::reactor::Notifier;
+use tracing::{error, trace};
 use {
     futures::{
-        future::{BoxFuture, FutureExt},
+        future::{FutureExt, LocalBoxFuture},
         task::{waker_ref, ArcWake},
     },
     std::{
+        any::Any,
+        cell::{Cell, RefCell, UnsafeCell},
         future::Future,
-        sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError},
+        mem,
+        panic::{catch_unwind, AssertUnwindSafe},
+        ptr,
+        rc::Rc,
+        sync::atomic::{AtomicBool, Ordering},
         sync::{Arc, Mutex},
         task::{Context, Poll},
+        thread::{self, ThreadId},
     },
 };
 
-/// Task executor that receives tasks off of a channel and runs them.
+/// Called with the payload of a panic caught while running a task.
+pub(crate) type PanicHook = Arc<dyn Fn(&(dyn Any + Send)) + Send + Sync>;
+
+// How many tasks to poll before checking for tasks woken from other threads,
+// so that they can't be starved by tasks that keep waking themselves
+const INJECTED_INTERVAL: usize = 61;
+
+/// Task executor that runs the tasks that have been woken, in the order they
+/// were woken.
 pub(crate) struct Executor {
-    ready_queue: Receiver<Arc<Task>>,
+    scheduler: Arc<Scheduler>,
+    panic_hook: Rc<RefCell<PanicHook>>,
 }
 
 impl Executor {
     // Returns true if the executor has more work to do
     pub fn tick(&self) -> bool {
-        let mut processed_events = false;
-        loop {
-            let result = self.ready_queue.try_recv();
-            match result {
-                Ok(task) => {
-                    processed_events = true;
-                    // Take the future, and if it has not yet completed (is still Some),
-                    // poll it in an attempt to complete it.
-                    let mut future_slot = task.future.lock().unwrap();
-                    if let Some(mut future) = future_slot.take() {
-                        // Create a `LocalWaker` from the task itself
-                        let waker = waker_ref(&task);
-                        let context = &mut Context::from_waker(&*waker);
-                        // `BoxFuture<T>` is a type alias for
-                        // `Pin<Box<dyn Future<Output = T> + Send + 'static>>`.
-                        // We can get a `Pin<&mut dyn Future + Send + 'static>`
-                        // from it by calling the `Pin::as_mut` method.
-                        trace!("polling future");
-                        if let Poll::Pending = future.as_mut().poll(context) {
-                            // We're not done processing the future, so put it
-                            // back in its task to be run again in the future.
-                            *future_slot = Some(future);
-                            trace!("future is still pending");
-                        } else {
-                            trace!("future finished");
-                        }
+        let mut polled = 0;
+        while let Some(task) = self.scheduler.next_task(polled % INJECTED_INTERVAL == 0) {
+            polled += 1;
+            debug_assert_eq!(task.scheduler.owner, current_thread());
+            // Cleared before polling so that the task is queued again if it
+            // wakes itself while it's being polled
+            task.queued.store(false, Ordering::Release);
+            // Safety: only this thread touches the future, and tasks
+            // are polled one at a time, so nothing else can be
+            // borrowing it
+            let future_slot = unsafe { &mut *task.future.get() };
+            if task.drop_only {
+                trace!("dropping the future of a task released on another thread");
+                *future_slot = None;
+                self.scheduler.finish_task();
+                continue;
+            }
+            // If the future has not yet completed (is still Some),
+            // poll it in an attempt to complete it.
+            if let Some(future) = future_slot.as_mut() {
+                // Create a `LocalWaker` from the task itself
+                let waker = waker_ref(&task);
+                let context = &mut Context::from_waker(&waker);
+                trace!("polling future");
+                // A panic only takes down the task that caused it, not
+                // the executor and every other task on this thread
+                match catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(context))) {
+                    // We're not done processing the future, so leave it
+                    // in its task to be run again in the future.
+                    Ok(Poll::Pending) => trace!("future is still pending"),
+                    Ok(Poll::Ready(())) => {
+                        trace!("future finished");
+                        *future_slot = None;
+                        self.scheduler.finish_task();
                     }
-                }
-                Err(TryRecvError::Disconnected) => {
-                    trace!("channel has no more connected senders");
-                    return false;
-                }
-                Err(TryRecvError::Empty) => {
-                    if processed_events {
-                        trace!("executor polled futures");
-                    } else {
-                        trace!("executor did not poll any futures");
+                    Err(payload) => {
+                        trace!("future panicked");
+                        *future_slot = None;
+                        self.scheduler.finish_task();
+                        report_panic(&self.panic_hook, &*payload);
                     }
-                    return processed_events;
                 }
             }
         }
+        if polled > 0 {
+            trace!("executor polled {} futures", polled);
+        } else {
+            trace!("executor did not poll any futures");
+        }
+        polled > 0
+    }
+
+    /// Whether any spawned tasks haven't finished yet.
+    pub fn has_pending_tasks(&self) -> bool {
+        self.scheduler.pending.get() > 0
+    }
+
+    pub fn set_panic_hook(&self, hook: PanicHook) {
+        *self.panic_hook.borrow_mut() = hook;
+    }
+
+    /// A waker for a future that the runtime polls itself, rather than
+    /// running it as a task. It starts out woken, so the future is polled
+    /// once before anything else.
+    pub fn block_on_waker(&self) -> Arc<BlockOnWaker> {
+        Arc::new(BlockOnWaker {
+            owner: self.scheduler.owner,
+            woken: AtomicBool::new(true),
+            notifier: self.scheduler.notifier.clone(),
+        })
     }
 }
 
-/// `Spawner` spawns new futures onto the task channel.
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.scheduler.close();
+    }
+}
+
+/// `Spawner` spawns new futures onto the executor's run queue. It can't be
+/// sent to other threads, because the tasks it spawns are owned by the thread
+/// it was created on.
 #[derive(Clone)]
 pub struct Spawner {
-    task_sender: SyncSender<Arc<Task>>,
+    scheduler: Arc<Scheduler>,
+    panic_hook: Rc<RefCell<PanicHook>>,
 }
 
 impl Spawner {
     pub fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) {
+        self.spawn_local(future);
+    }
+
+    pub fn spawn_local(&self, future: impl Future<Output = ()> + 'static) {
         trace!("spawning future");
-        let future = future.boxed();
+        if self.scheduler.closed.load(Ordering::Acquire) {
+            panic!("cannot spawn future because its runtime has shut down");
+        }
+        let future = future.boxed_local();
         let task = Arc::new(Task {
-            future: Mutex::new(Some(future)),
-            task_sender: self.task_sender.clone(),
+            future: UnsafeCell::new(Some(future)),
+            scheduler: self.scheduler.clone(),
+            queued: AtomicBool::new(true),
+            next: Cell::new(ptr::null()),
+            drop_only: false,
         });
-        self.task_sender
-            .send(task)
-            .expect("cannot spawn future because there is no receiver on the channel");
+        self.scheduler.pending.set(self.scheduler.pending.get() + 1);
+        self.scheduler.schedule(task);
+    }
+
+    /// Reports a panic that was caught outside of the executor (for example
+    /// by the server, which catches panics from handlers itself).
+    pub fn report_panic(&self, payload: &(dyn Any + Send)) {
+        report_panic(&self.panic_hook, payload);
+    }
+}
+
+/// The queues of tasks that have been woken and are waiting to be polled.
+struct Scheduler {
+    /// The thread running the executor, which owns all of its tasks.
+    owner: ThreadId,
+
+    /// Tasks woken on the owner thread. Only the owner thread touches this,
+    /// so it doesn't need to be locked.
+    local: RunQueue,
+
+    /// Tasks woken from other threads. The executor moves these to the local
+    /// queue periodically, and whenever it runs out of local tasks.
+    injected: Mutex<RunQueue>,
+
+    /// Wakes the reactor when a task is woken from another thread.
+    notifier: Arc<Notifier>,
+
+    /// The number of spawned tasks that haven't finished, including tasks
+    /// that were dropped without finishing. Only the owner thread touches
+    /// this.
+    pending: Cell<usize>,
+
+    /// Set once the executor has been dropped, after which woken tasks are no
+    /// longer queued.
+    closed: AtomicBool,
+}
+
+// Safety: the local queue is only used on the owner thread, and the injected
+// queue is only used while it's locked.
+unsafe impl Send for Scheduler {}
+unsafe impl Sync for Scheduler {}
+
+impl Scheduler {
+    fn schedule(&self, task: Arc<Task>) {
+        if current_thread() == self.owner {
+            if !self.closed.load(Ordering::Acquire) {
+                self.local.push(task);
+            }
+        } else {
+            // Checked under the lock so a task can't be queued after the
+            // executor has emptied the queue in `close`
+            let injected = self.injected.lock().unwrap();
+            if self.closed.load(Ordering::Acquire) {
+                return;
+            }
+            injected.push(task);
+            drop(injected);
+            // The owner thread may be blocked waiting for I/O
+            self.notifier.notify();
+        }
+    }
+
+    fn finish_task(&self) {
+        self.pending.set(self.pending.get() - 1);
+    }
+
+    // Sends the future of a task whose last reference was dropped on another
+    // thread back to the owner thread, to be dropped there
+    fn drop_on_owner(self: &Arc<Self>, future: LocalBoxFuture<'static, ()>) {
+        let task = Arc::new(Task {
+            future: UnsafeCell::new(Some(future)),
+            scheduler: self.clone(),
+            queued: AtomicBool::new(true),
+            next: Cell::new(ptr::null()),
+            drop_only: true,
+        });
+        let injected = self.injected.lock().unwrap();
+        if self.closed.load(Ordering::Acquire) {
+            drop(injected);
+            // The executor is gone, so there's no thread left that can drop
+            // the future safely
+            if let Some(future) = unsafe { &mut *task.future.get() }.take() {
+                mem::forget(future);
+            }
+            return;
+        }
+        injected.push(task);
+        drop(injected);
+        self.notifier.notify();
+    }
+
+    fn take_injected(&self) {
+        // Cleared first so that tasks injected after this notify again
+        self.notifier.clear();
+        self.local.append(&self.injected.lock().unwrap());
+    }
+
+    fn next_task(&self, check_injected: bool) -> Option<Arc<Task>> {
+        if check_injected {
+            self.take_injected();
+        }
+        self.local.pop().or_else(|| {
+            self.take_injected();
+            self.local.pop()
+        })
+    }
+
+    // Queued tasks hold a reference to the scheduler, so they have to be
+    // dropped when the executor is or they would never be freed
+    fn close(&self) {
+        let injected = RunQueue::new();
+        {
+            let queue = self.injected.lock().unwrap();
+            self.closed.store(true, Ordering::Release);
+            injected.append(&queue);
+        }
+        // Dropping the tasks' futures can wake other tasks, which is why
+        // this happens outside of the lock
+        drop(injected);
+        while self.local.pop().is_some() {}
+    }
+}
+
+/// An intrusive FIFO queue of tasks, linked through `Task::next`. A task is
+/// only ever in one queue at a time (see `Task::queued`), so pushing a task
+/// never allocates and the queue can grow as large as the number of tasks.
+struct RunQueue {
+    head: Cell<*const Task>,
+    tail: Cell<*const Task>,
+}
+
+impl RunQueue {
+    fn new() -> RunQueue {
+        RunQueue {
+            head: Cell::new(ptr::null()),
+            tail: Cell::new(ptr::null()),
+        }
+    }
+
+    fn push(&self, task: Arc<Task>) {
+        // The queue holds the reference until the task is popped
+        let task = Arc::into_raw(task);
+        unsafe {
+            (*task).next.set(ptr::null());
+            match self.tail.get().as_ref() {
+                Some(tail) => tail.next.set(task),
+                None => self.head.set(task),
+            }
+        }
+        self.tail.set(task);
+    }
+
+    fn pop(&self) -> Option<Arc<Task>> {
+        let task = self.head.get();
+        if task.is_null() {
+            return None;
+        }
+        unsafe {
+            self.head.set((*task).next.get());
+            if self.head.get().is_null() {
+                self.tail.set(ptr::null());
+            }
+            Some(Arc::from_raw(task))
+        }
+    }
+
+    // Moves all of the tasks in `other` to the back of this queue
+    fn append(&self, other: &RunQueue) {
+        let head = other.head.replace(ptr::null());
+        if head.is_null() {
+            return;
+        }
+        match unsafe { self.tail.get().as_ref() } {
+            Some(tail) => tail.next.set(head),
+            None => self.head.set(head),
+        }
+        self.tail.set(other.tail.replace(ptr::null()));
+    }
+}
+
+impl Drop for RunQueue {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
     }
 }
 
@@ -89,35 +333,223 @@ impl Spawner {
 struct Task {
     /// In-progress future that should be pushed to completion.
     ///
-    /// The `Mutex` is not necessary for correctness, since we only have
-    /// one thread executing tasks at once. However, Rust isn't smart
-    /// enough to know that `future` is only mutated from one thread,
-    /// so we need to use the `Mutex` to prove thread-safety. A production
-    /// executor would not need this, and could use `UnsafeCell` instead.
-    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    /// The future may not be `Send`, so it is only ever polled or dropped on
+    /// the thread that owns the executor. That is also what makes it safe to
+    /// use an `UnsafeCell` rather than locking the future on every poll.
+    future: UnsafeCell<Option<LocalBoxFuture<'static, ()>>>,
+
+    /// The scheduler to place the task itself back onto when it's woken.
+    scheduler: Arc<Scheduler>,
+
+    /// Whether the task is already in one of the scheduler's queues, so that
+    /// waking it again doesn't queue it twice.
+    queued: AtomicBool,
+
+    /// The next task in the queue this task is in.
+    next: Cell<*const Task>,
+
+    /// Set for a task that only carries the future of another task back to
+    /// the owner thread so it can be dropped there, rather than polled.
+    drop_only: bool,
+}
+
+// Safety: a task's waker can be sent to and called from any thread, but that
+// only clones the `Arc` and places it in the owning thread's queues. The
+// future itself is never polled or dropped off of that thread (see `Drop`
+// below), and
+// `next` is only used by whichever queue the task is in.
+unsafe impl Send for Task {}
+unsafe impl Sync for Task {}
 
-    /// Handle to place the task itself back onto the task queue.
-    task_sender: SyncSender<Arc<Task>>,
+impl Drop for Task {
+    fn drop(&mut self) {
+        let future = match self.future.get_mut().take() {
+            Some(future) => future,
+            None => return,
+        };
+        if current_thread() == self.scheduler.owner {
+            // The task can no longer finish, for example because nothing
+            // kept a waker for it, so it stops counting as pending
+            self.scheduler.finish_task();
+            drop(future);
+        } else {
+            // The last reference to a task can be a waker on another thread.
+            // Dropping the future there could touch state that isn't thread
+            // safe, so the owner thread drops it instead.
+            self.scheduler.drop_on_owner(future);
+        }
+    }
 }
 
 impl ArcWake for Task {
     fn wake_by_ref(arc_self: &Arc<Self>) {
-        // Implement `wake` by sending this task back onto the task channel
-        // so that it will be polled again by the executor.
-        let cloned = arc_self.clone();
         trace!("waking task");
-        arc_self
-            .task_sender
-            .send(cloned)
-            .expect("cannot wake task because there is no receiver on the channel");
+        // Implement `wake` by placing this task back onto the run queue so
+        // that it will be polled again by the executor, unless it's already
+        // waiting to be polled.
+        if !arc_self.queued.swap(true, Ordering::AcqRel) {
+            arc_self.scheduler.schedule(arc_self.clone());
+        }
+    }
+}
+
+/// Wakes the future passed to `Runtime::block_on`.
+pub(crate) struct BlockOnWaker {
+    owner: ThreadId,
+    woken: AtomicBool,
+    notifier: Arc<Notifier>,
+}
+
+impl BlockOnWaker {
+    pub fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+
+    // Cleared before polling, like `Task::queued`
+    pub fn take_woken(&self) -> bool {
+        self.woken.swap(false, Ordering::AcqRel)
+    }
+}
+
+impl ArcWake for BlockOnWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // On the owner thread, the runtime checks the flag before it waits
+        // for I/O, so only wakes from other threads need to interrupt it
+        if !arc_self.woken.swap(true, Ordering::AcqRel) && current_thread() != arc_self.owner {
+            arc_self.notifier.notify();
+        }
     }
 }
 
-pub(crate) fn new_executor_and_spawner() -> (Executor, Spawner) {
-    // Maximum number of tasks to allow queueing in the channel at once.
-    // This is just to make `sync_channel` happy, and wouldn't be present in
-    // a real executor.
-    const MAX_QUEUED_TASKS: usize = 10_000;
-    let (task_sender, ready_queue) = sync_channel(MAX_QUEUED_TASKS);
-    (Executor { ready_queue }, Spawner { task_sender })
+pub(crate) fn new_executor_and_spawner(notifier: Arc<Notifier>) -> (Executor, Spawner) {
+    let scheduler = Arc::new(Scheduler {
+        owner: current_thread(),
+        local: RunQueue::new(),
+        injected: Mutex::new(RunQueue::new()),
+        notifier,
+        pending: Cell::new(0),
+        closed: AtomicBool::new(false),
+    });
+    let default_hook: PanicHook = Arc::new(log_panic);
+    let panic_hook = Rc::new(RefCell::new(default_hook));
+    (
+        Executor {
+            scheduler: scheduler.clone(),
+            panic_hook: panic_hook.clone(),
+        },
+        Spawner {
+            scheduler,
+            panic_hook,
+        },
+    )
+}
+
+// `thread::current()` clones a handle to the thread, which is slow enough to
+// matter on every wake, so the id is cached
+fn current_thread() -> ThreadId {
+    thread_local!(static CURRENT_THREAD: ThreadId = thread::current().id());
+    CURRENT_THREAD.with(|id| *id)
+}
+
+fn report_panic(panic_hook: &RefCell<PanicHook>, payload: &(dyn Any + Send)) {
+    // The hook is cloned out so it can replace itself without a double borrow
+    let hook = panic_hook.borrow().clone();
+    hook(payload);
+}
+
+fn log_panic(payload: &(dyn Any + Send)) {
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "Box<dyn Any>"
+    };
+    error!("task panicked: {}", message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reactor::{Reactor, RingConfig};
+    use futures::future;
+    use std::task::Waker;
+
+    fn executor() -> (Reactor, Executor, Spawner) {
+        let (reactor, _) = Reactor::new(&RingConfig::default()).unwrap();
+        let (executor, spawner) = new_executor_and_spawner(reactor.notifier());
+        (reactor, executor, spawner)
+    }
+
+    #[test]
+    fn finished_task_is_not_pending() {
+        let (_reactor, executor, spawner) = executor();
+        spawner.spawn_local(async {});
+        assert!(executor.has_pending_tasks());
+        executor.tick();
+        assert!(!executor.has_pending_tasks());
+    }
+
+    #[test]
+    fn task_dropped_without_finishing_is_not_pending() {
+        let (_reactor, executor, spawner) = executor();
+        // Nothing keeps a waker, so the task is dropped once it's polled
+        spawner.spawn_local(future::pending());
+        executor.tick();
+        assert!(!executor.has_pending_tasks());
+    }
+
+    type Slot<T> = Arc<Mutex<Option<T>>>;
+
+    // Records the thread that a task's future is dropped on
+    struct DropThread(Slot<ThreadId>);
+
+    impl Drop for DropThread {
+        fn drop(&mut self) {
+            *self.0.lock().unwrap() = Some(current_thread());
+        }
+    }
+
+    // Spawns a task that keeps its waker in `waker` and never finishes
+    fn spawn_waiting(spawner: &Spawner) -> (Slot<Waker>, Slot<ThreadId>) {
+        let waker: Slot<Waker> = Arc::default();
+        let dropped_on: Slot<ThreadId> = Arc::default();
+        let stored = waker.clone();
+        let guard = DropThread(dropped_on.clone());
+        spawner.spawn_local(future::poll_fn(move |cx| {
+            let _guard = &guard;
+            *stored.lock().unwrap() = Some(cx.waker().clone());
+            Poll::<()>::Pending
+        }));
+        (waker, dropped_on)
+    }
+
+    #[test]
+    fn task_dropped_on_another_thread_is_dropped_on_its_own() {
+        let (_reactor, executor, spawner) = executor();
+        let (waker, dropped_on) = spawn_waiting(&spawner);
+        executor.tick();
+        assert!(executor.has_pending_tasks());
+
+        let waker = waker.lock().unwrap().take().unwrap();
+        thread::spawn(move || drop(waker)).join().unwrap();
+        assert_eq!(*dropped_on.lock().unwrap(), None);
+
+        // The future comes back to be dropped by the executor
+        executor.tick();
+        assert!(!executor.has_pending_tasks());
+        assert_eq!(*dropped_on.lock().unwrap(), Some(current_thread()));
+    }
+
+    #[test]
+    fn task_sent_back_after_shutdown_is_dropped_with_the_executor() {
+        let (_reactor, executor, spawner) = executor();
+        let (waker, dropped_on) = spawn_waiting(&spawner);
+        executor.tick();
+
+        let waker = waker.lock().unwrap().take().unwrap();
+        thread::spawn(move || drop(waker)).join().unwrap();
+        drop(executor);
+        assert_eq!(*dropped_on.lock().unwrap(), Some(current_thread()));
+    }
 }
@@ -0,0 +1,546 @@
+//! Typed extractors that let handlers take parts of the request as arguments.
+//!
+//! Requires the `serde` cargo feature.
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! struct Search {
+//!     q: String,
+//! }
+//!
+//! let router = Router::new().get(
+//!     "/users/:id/posts",
+//!     extract(|Path(id): Path<u32>, Query(search): Query<Search>| async move {
+//!         Response::new(format!("{} {}", id, search.q).into_bytes())
+//!     }),
+//! );
+//! ```
+//!
+//! If an extractor fails, the handler is not called and the client gets an
+//! error response instead: 400 for malformed input, 415 for a body with the
+//! wrong `Content-Type`, and 422 for a body that is well formed but doesn't
+//! match the expected type.
+
+mod path;
+
+use crate::handler::Handler;
+use crate::http_server::PeerAddr;
+use crate::response::IntoResponse;
+use crate::router::Params;
+use futures::future::{ready, Either, Future, FutureExt, Map, Ready};
+use http::header::{HeaderName, CONTENT_TYPE};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use tracing::{debug, error};
+
+/// Types that can be created from a request, to be used as handler arguments.
+pub trait FromRequest: Sized {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection>;
+}
+
+/// The error response sent when an extractor fails.
+#[derive(Debug)]
+pub struct Rejection {
+    status: StatusCode,
+    message: String,
+}
+
+impl Rejection {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Rejection {
+        Rejection {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response<Vec<u8>> {
+        (self.status, self.message).into_response()
+    }
+}
+
+/// A handler made from a function that takes extractors as arguments.
+/// Created with `extract`.
+pub struct Extract<F, T> {
+    f: F,
+    args: PhantomData<fn() -> T>,
+}
+
+/// Turns a function whose arguments all implement `FromRequest` into a
+/// `Handler`. Functions can take up to six arguments.
+pub fn extract<F, T>(f: F) -> Extract<F, T> {
+    Extract {
+        f,
+        args: PhantomData,
+    }
+}
+
+macro_rules! impl_handler {
+    ($($arg:ident),+) => {
+        impl<F, R, $($arg,)+> Handler for Extract<F, ($($arg,)+)>
+        where
+            F: (Fn($($arg),+) -> R) + 'static + Send + Sync,
+            R: Future + 'static,
+            R::Output: IntoResponse,
+            $($arg: FromRequest + 'static,)+
+        {
+            type Future = Either<
+                Map<R, fn(R::Output) -> Response<Vec<u8>>>,
+                Ready<Response<Vec<u8>>>,
+            >;
+
+            #[allow(non_snake_case)]
+            fn call(&self, request: Request<&[u8]>) -> Self::Future {
+                $(
+                    let $arg = match $arg::from_request(&request) {
+                        Ok(value) => value,
+                        Err(rejection) => {
+                            debug!("rejecting request: {}", rejection.message);
+                            return Either::Right(ready(rejection.into_response()));
+                        }
+                    };
+                )+
+                Either::Left((self.f)($($arg),+).map(IntoResponse::into_response))
+            }
+        }
+    };
+}
+
+impl_handler!(A);
+impl_handler!(A, B);
+impl_handler!(A, B, C);
+impl_handler!(A, B, C, D);
+impl_handler!(A, B, C, D, E);
+impl_handler!(A, B, C, D, E, G);
+
+/// Deserializes the query string.
+#[derive(Clone, Debug)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        let query = request.uri().query().unwrap_or("");
+        serde_urlencoded::from_str(query).map(Query).map_err(|err| {
+            Rejection::new(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid query string: {}", err),
+            )
+        })
+    }
+}
+
+/// Deserializes a request body with the `application/json` content type.
+/// When returned from a handler, serializes the response body.
+#[derive(Clone, Debug)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        if !has_content_type(request, "application/json") && !has_json_suffix(request) {
+            return Err(Rejection::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected a request with Content-Type: application/json",
+            ));
+        }
+        serde_json::from_slice(request.body())
+            .map(Json)
+            .map_err(|err| {
+                // Bodies that are valid JSON of the wrong shape are well formed,
+                // so they get a 422 rather than a 400
+                let status = if err.is_data() {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
+                Rejection::new(status, format!("Invalid JSON body: {}", err))
+            })
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response<Vec<u8>> {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => (
+                [(CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+                body,
+            )
+                .into_response(),
+            Err(err) => {
+                error!("Unable to serialize JSON response: {}", err);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+/// Deserializes a request body with the
+/// `application/x-www-form-urlencoded` content type.
+#[derive(Clone, Debug)]
+pub struct Form<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Form<T> {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        if !has_content_type(request, "application/x-www-form-urlencoded") {
+            return Err(Rejection::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected a request with Content-Type: application/x-www-form-urlencoded",
+            ));
+        }
+        serde_urlencoded::from_bytes(request.body())
+            .map(Form)
+            .map_err(|err| {
+                Rejection::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Invalid form body: {}", err),
+                )
+            })
+    }
+}
+
+/// Deserializes the path parameters matched by the `Router`.
+///
+/// `T` can be a struct or map, which is filled in by parameter name, a tuple,
+/// which is filled in by position, or a single value if the route has
+/// exactly one parameter.
+#[derive(Clone, Debug)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Path<T> {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        // Params are only missing if the handler isn't behind a Router,
+        // which is a mistake in the server rather than the request
+        let params = request.extensions().get::<Params>().ok_or_else(|| {
+            Rejection::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Path parameters are only available to handlers behind a Router",
+            )
+        })?;
+        T::deserialize(path::PathDeserializer::new(params))
+            .map(Path)
+            .map_err(|err| {
+                Rejection::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid path parameters: {}", err),
+                )
+            })
+    }
+}
+
+/// A header that can be extracted with `Header`.
+pub trait TypedHeader: Sized {
+    fn name() -> HeaderName;
+
+    /// Parses the header, returning None if the value is invalid.
+    fn decode(value: &HeaderValue) -> Option<Self>;
+}
+
+/// Parses the header named by `T::name()`. The request is rejected if the
+/// header is missing or invalid; use `Option<Header<T>>` for an optional one.
+#[derive(Clone, Debug)]
+pub struct Header<T>(pub T);
+
+impl<T: TypedHeader> FromRequest for Header<T> {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        let name = T::name();
+        let value = request.headers().get(&name).ok_or_else(|| {
+            Rejection::new(StatusCode::BAD_REQUEST, format!("Missing header: {}", name))
+        })?;
+        T::decode(value).map(Header).ok_or_else(|| {
+            Rejection::new(StatusCode::BAD_REQUEST, format!("Invalid header: {}", name))
+        })
+    }
+}
+
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        Ok(T::from_request(request).ok())
+    }
+}
+
+impl FromRequest for Method {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        Ok(request.method().clone())
+    }
+}
+
+impl FromRequest for Uri {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        Ok(request.uri().clone())
+    }
+}
+
+impl FromRequest for HeaderMap {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        Ok(request.headers().clone())
+    }
+}
+
+impl FromRequest for Params {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        Ok(request
+            .extensions()
+            .get::<Params>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+impl FromRequest for PeerAddr {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        request
+            .extensions()
+            .get::<PeerAddr>()
+            .cloned()
+            .ok_or_else(|| {
+                Rejection::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "The peer address is not known for this connection",
+                )
+            })
+    }
+}
+
+/// The raw request body.
+impl FromRequest for Vec<u8> {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        Ok(request.body().to_vec())
+    }
+}
+
+/// The request body, which must be valid UTF-8.
+impl FromRequest for String {
+    fn from_request(request: &Request<&[u8]>) -> Result<Self, Rejection> {
+        String::from_utf8(request.body().to_vec())
+            .map_err(|_| Rejection::new(StatusCode::BAD_REQUEST, "Request body is not valid UTF-8"))
+    }
+}
+
+// The media type, without parameters like charset
+fn media_type<'a>(request: &'a Request<&[u8]>) -> Option<&'a str> {
+    request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or("").trim())
+}
+
+fn has_content_type(request: &Request<&[u8]>, expected: &str) -> bool {
+    media_type(request).is_some_and(|media_type| media_type.eq_ignore_ascii_case(expected))
+}
+
+// Types like application/problem+json are JSON too
+fn has_json_suffix(request: &Request<&[u8]>) -> bool {
+    media_type(request).is_some_and(|media_type| {
+        let media_type = media_type.to_ascii_lowercase();
+        media_type.starts_with("application/") && media_type.ends_with("+json")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Search {
+        q: String,
+        page: u32,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Count(u32);
+
+    impl TypedHeader for Count {
+        fn name() -> HeaderName {
+            HeaderName::from_static("x-count")
+        }
+
+        fn decode(value: &HeaderValue) -> Option<Count> {
+            value.to_str().ok()?.parse().ok().map(Count)
+        }
+    }
+
+    fn request(
+        uri: &str,
+        content_type: Option<&str>,
+        body: &'static [u8],
+    ) -> Request<&'static [u8]> {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(content_type) = content_type {
+            builder = builder.header(CONTENT_TYPE, content_type);
+        }
+        builder.body(body).unwrap()
+    }
+
+    // The status of the response sent when the extractor fails
+    fn rejection<T: FromRequest>(request: &Request<&[u8]>) -> StatusCode {
+        match T::from_request(request) {
+            Ok(_) => panic!("extractor accepted the request"),
+            Err(rejection) => rejection.status(),
+        }
+    }
+
+    #[test]
+    fn query_is_deserialized() {
+        let Query(search) =
+            Query::<Search>::from_request(&request("/?q=rust&page=2", None, b"")).unwrap();
+        assert_eq!(
+            search,
+            Search {
+                q: "rust".to_string(),
+                page: 2
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_query_is_a_bad_request() {
+        let status = rejection::<Query<Search>>(&request("/?q=rust&page=two", None, b""));
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let status = rejection::<Query<Search>>(&request("/", None, b""));
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn json_is_deserialized() {
+        let body = br#"{"q":"rust","page":2}"#;
+        for content_type in [
+            "application/json",
+            "Application/JSON; charset=utf-8",
+            "application/problem+json",
+        ] {
+            let Json(search) =
+                Json::<Search>::from_request(&request("/", Some(content_type), body)).unwrap();
+            assert_eq!(search.page, 2);
+        }
+    }
+
+    #[test]
+    fn json_needs_a_json_content_type() {
+        let body = br#"{"q":"rust","page":2}"#;
+        let status = rejection::<Json<Search>>(&request("/", Some("text/plain"), body));
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let status = rejection::<Json<Search>>(&request("/", None, body));
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn malformed_json_is_a_bad_request() {
+        let request = request("/", Some("application/json"), br#"{"q":"rust","#);
+        assert_eq!(rejection::<Json<Search>>(&request), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn json_of_the_wrong_shape_is_unprocessable() {
+        let request = request(
+            "/",
+            Some("application/json"),
+            br#"{"q":"rust","page":"two"}"#,
+        );
+        assert_eq!(
+            rejection::<Json<Search>>(&request),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn form_is_deserialized() {
+        let request = request(
+            "/",
+            Some("application/x-www-form-urlencoded"),
+            b"q=hello+world&page=3",
+        );
+        let Form(search) = Form::<Search>::from_request(&request).unwrap();
+        assert_eq!(
+            search,
+            Search {
+                q: "hello world".to_string(),
+                page: 3
+            }
+        );
+    }
+
+    #[test]
+    fn form_needs_a_form_content_type() {
+        let body = b"q=rust&page=2";
+        let status = rejection::<Form<Search>>(&request("/", Some("application/json"), body));
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let status = rejection::<Form<Search>>(&request("/", None, body));
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn invalid_form_is_unprocessable() {
+        let request = request(
+            "/",
+            Some("application/x-www-form-urlencoded"),
+            b"q=rust&page=two",
+        );
+        assert_eq!(
+            rejection::<Form<Search>>(&request),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn header_is_decoded() {
+        let request = Request::builder()
+            .header("x-count", "42")
+            .body(&b""[..])
+            .unwrap();
+        let Header(count) = Header::<Count>::from_request(&request).unwrap();
+        assert_eq!(count, Count(42));
+    }
+
+    #[test]
+    fn missing_or_invalid_header_is_a_bad_request() {
+        let missing = request("/", None, b"");
+        assert_eq!(
+            rejection::<Header<Count>>(&missing),
+            StatusCode::BAD_REQUEST
+        );
+        assert!(Option::<Header<Count>>::from_request(&missing)
+            .unwrap()
+            .is_none());
+
+        let invalid = Request::builder()
+            .header("x-count", "many")
+            .body(&b""[..])
+            .unwrap();
+        assert_eq!(
+            rejection::<Header<Count>>(&invalid),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn path_is_deserialized_from_params() {
+        let mut request = request("/users/7", None, b"");
+        request
+            .extensions_mut()
+            .insert(Params(vec![("id".to_string(), "7".to_string())]));
+        let Path(id) = Path::<u32>::from_request(&request).unwrap();
+        assert_eq!(id, 7);
+        assert_eq!(rejection::<Path<bool>>(&request), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn path_outside_a_router_is_a_server_error() {
+        let request = request("/users/7", None, b"");
+        assert_eq!(
+            rejection::<Path<u32>>(&request),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
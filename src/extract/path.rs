@@ -0,0 +1,305 @@
+use crate::router::Params;
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use std::fmt;
+
+#[derive(Debug)]
+pub(crate) struct PathError(String);
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl de::Error for PathError {
+    fn custom<T: fmt::Display>(msg: T) -> PathError {
+        PathError(msg.to_string())
+    }
+}
+
+/// Deserializes `Params` as a map (or struct) by name, as a sequence (or
+/// tuple) by position, or as a single value if there is only one parameter.
+pub(crate) struct PathDeserializer<'de> {
+    params: &'de Params,
+}
+
+impl<'de> PathDeserializer<'de> {
+    pub(crate) fn new(params: &'de Params) -> PathDeserializer<'de> {
+        PathDeserializer { params }
+    }
+
+    fn single(&self) -> Result<Value<'de>, PathError> {
+        let mut values = self.params.iter().map(|(_, value)| value);
+        match (values.next(), values.next()) {
+            (Some(value), None) => Ok(Value(value)),
+            _ => Err(PathError(format!(
+                "expected 1 path parameter, found {}",
+                self.params.iter().count()
+            ))),
+        }
+    }
+}
+
+// Single values are deserialized from the only parameter
+macro_rules! forward_to_single {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PathError> {
+                self.single()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for PathDeserializer<'de> {
+    type Error = PathError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PathError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PathError> {
+        visitor.visit_map(MapDeserializer::new(
+            self.params.iter().map(|(name, value)| (name, Value(value))),
+        ))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, PathError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PathError> {
+        visitor.visit_seq(SeqDeserializer::new(
+            self.params.iter().map(|(_, value)| Value(value)),
+        ))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, PathError> {
+        let count = self.params.iter().count();
+        if count != len {
+            return Err(PathError(format!(
+                "expected {} path parameters, found {}",
+                len, count
+            )));
+        }
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, PathError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, PathError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, PathError> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_single! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_f32 deserialize_f64 deserialize_char deserialize_str deserialize_string
+        deserialize_bytes deserialize_byte_buf deserialize_option deserialize_identifier
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 unit unit_struct ignored_any
+    }
+}
+
+/// A single parameter value, which is parsed into whatever type is asked for.
+struct Value<'de>(&'de str);
+
+macro_rules! parse_value {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PathError> {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => Err(PathError(format!("cannot parse {:?}", self.0))),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Value<'de> {
+    type Error = PathError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PathError> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PathError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, PathError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, PathError> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    parse_value! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, PathError> for Value<'de> {
+    type Deserializer = Value<'de>;
+
+    fn into_deserializer(self) -> Value<'de> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Post {
+        user: String,
+        id: u32,
+        draft: Option<bool>,
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Params {
+        Params(
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn deserialize<'de, T: Deserialize<'de>>(params: &'de Params) -> Result<T, PathError> {
+        T::deserialize(PathDeserializer::new(params))
+    }
+
+    #[test]
+    fn single_value_is_parsed() {
+        let params = params(&[("id", "7")]);
+        assert_eq!(deserialize::<u32>(&params).unwrap(), 7);
+        assert_eq!(deserialize::<String>(&params).unwrap(), "7");
+        assert_eq!(deserialize::<Option<i64>>(&params).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn single_value_needs_exactly_one_param() {
+        let err = deserialize::<u32>(&params(&[("user", "ann"), ("id", "7")])).unwrap_err();
+        assert_eq!(err.to_string(), "expected 1 path parameter, found 2");
+        let err = deserialize::<u32>(&params(&[])).unwrap_err();
+        assert_eq!(err.to_string(), "expected 1 path parameter, found 0");
+    }
+
+    #[test]
+    fn tuple_is_filled_by_position() {
+        let params = params(&[("user", "ann"), ("id", "7")]);
+        let (user, id) = deserialize::<(String, u32)>(&params).unwrap();
+        assert_eq!((user.as_str(), id), ("ann", 7));
+    }
+
+    #[test]
+    fn tuple_needs_one_param_per_element() {
+        let params = params(&[("user", "ann"), ("id", "7")]);
+        let err = deserialize::<(String, u32, u32)>(&params).unwrap_err();
+        assert_eq!(err.to_string(), "expected 3 path parameters, found 2");
+    }
+
+    #[test]
+    fn struct_is_filled_by_name() {
+        let plain = params(&[("id", "7"), ("user", "ann")]);
+        assert_eq!(
+            deserialize::<Post>(&plain).unwrap(),
+            Post {
+                user: "ann".to_string(),
+                id: 7,
+                draft: None
+            }
+        );
+        let drafted = params(&[("id", "7"), ("user", "ann"), ("draft", "true")]);
+        assert_eq!(deserialize::<Post>(&drafted).unwrap().draft, Some(true));
+    }
+
+    #[test]
+    fn struct_with_missing_field_is_rejected() {
+        let err = deserialize::<Post>(&params(&[("id", "7")])).unwrap_err();
+        assert!(err.to_string().contains("user"), "{}", err);
+    }
+
+    #[test]
+    fn map_gets_every_param() {
+        let params = params(&[("user", "ann"), ("id", "7")]);
+        let map = deserialize::<HashMap<String, String>>(&params).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["user"], "ann");
+    }
+
+    #[test]
+    fn mismatched_type_is_rejected() {
+        let err = deserialize::<u32>(&params(&[("id", "seven")])).unwrap_err();
+        assert_eq!(err.to_string(), r#"cannot parse "seven""#);
+        let params = params(&[("user", "ann"), ("id", "seven")]);
+        assert!(deserialize::<(String, u32)>(&params).is_err());
+        assert!(deserialize::<Post>(&params).is_err());
+    }
+}
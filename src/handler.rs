@@ -0,0 +1,396 @@
+use crate::body::{self, BodyStream, RequestBody};
+use crate::response::IntoResponse;
+use futures::future::{Future, FutureExt, LocalBoxFuture, Map};
+use http::{Request, Response};
+use std::sync::Arc;
+
+/// Handles HTTP requests for the `HttpServer`.
+///
+/// This is implemented for closures of the form
+/// `Fn(Request<&[u8]>) -> impl Future<Output = impl IntoResponse>`,
+/// as well as for the `Router`, `Proxy` and handlers wrapped in `Layer`s.
+///
+/// The handler itself is shared between the server's threads, but each
+/// request's future runs on the thread that accepted the connection, so it
+/// doesn't need to be `Send`.
+pub trait Handler: 'static + Send + Sync {
+    type Future: Future<Output = Response<Vec<u8>>> + 'static;
+
+    fn call(&self, request: Request<&[u8]>) -> Self::Future;
+
+    /// Called with the head of a request that has `Expect: 100-continue`,
+    /// before the client has sent the body. Returning a response (such as a
+    /// 417 or 413) sends it without reading the body and closes the
+    /// connection. By default the server tells the client to send the body.
+    fn check_continue(&self, request: &Request<()>) -> Option<Response<Vec<u8>>> {
+        let _ = request;
+        None
+    }
+
+    /// Called with the head of each request, before its body has been read.
+    /// Returning a `StreamingCall` passes the request to it instead of to
+    /// `call`, with a body that is read from the connection as the call
+    /// consumes it. This is for handlers that forward bodies instead of
+    /// using them, like the `Proxy`.
+    fn streaming(&self, request: &Request<()>) -> Option<StreamingCall> {
+        let _ = request;
+        None
+    }
+
+    /// Called when the server accepts a connection. Returning a
+    /// `ConnectionHandler` passes the connection's requests to it instead of
+    /// to `call`, which lets the handler keep state for the connection.
+    fn connection_handler(&self) -> Option<ConnectionHandler> {
+        None
+    }
+}
+
+/// Handles a single request whose body is read from the connection as it's
+/// consumed, returning a response whose body is sent as it's produced.
+pub type StreamingCall =
+    Box<dyn FnOnce(Request<RequestBody>) -> LocalBoxFuture<'static, Response<BodyStream>>>;
+
+/// Handles the requests on a single connection, one at a time. Unlike a
+/// `Handler` it isn't shared between threads, so it can hold state that
+/// isn't `Send` or `Sync`.
+pub type ConnectionHandler =
+    Box<dyn FnMut(Request<&[u8]>) -> LocalBoxFuture<'static, Response<Vec<u8>>>>;
+
+impl<F, R> Handler for F
+where
+    F: (Fn(Request<&[u8]>) -> R) + 'static + Send + Sync,
+    R: Future + 'static,
+    R::Output: IntoResponse,
+{
+    type Future = Map<R, fn(R::Output) -> Response<Vec<u8>>>;
+
+    fn call(&self, request: Request<&[u8]>) -> Self::Future {
+        (self)(request).map(IntoResponse::into_response)
+    }
+}
+
+impl<H: Handler> Handler for Arc<H> {
+    type Future = H::Future;
+
+    fn call(&self, request: Request<&[u8]>) -> H::Future {
+        (**self).call(request)
+    }
+
+    fn check_continue(&self, request: &Request<()>) -> Option<Response<Vec<u8>>> {
+        (**self).check_continue(request)
+    }
+
+    fn streaming(&self, request: &Request<()>) -> Option<StreamingCall> {
+        (**self).streaming(request)
+    }
+
+    fn connection_handler(&self) -> Option<ConnectionHandler> {
+        (**self).connection_handler()
+    }
+}
+
+/// A handler whose future type has been erased, so that handlers of
+/// different types can be stored together.
+pub type BoxHandler = Box<dyn Handler<Future = LocalBoxFuture<'static, Response<Vec<u8>>>>>;
+
+struct Boxed<H>(H);
+
+impl<H: Handler> Handler for Boxed<H> {
+    type Future = LocalBoxFuture<'static, Response<Vec<u8>>>;
+
+    fn call(&self, request: Request<&[u8]>) -> Self::Future {
+        self.0.call(request).boxed_local()
+    }
+
+    fn check_continue(&self, request: &Request<()>) -> Option<Response<Vec<u8>>> {
+        self.0.check_continue(request)
+    }
+
+    fn streaming(&self, request: &Request<()>) -> Option<StreamingCall> {
+        self.0.streaming(request)
+    }
+
+    fn connection_handler(&self) -> Option<ConnectionHandler> {
+        self.0.connection_handler()
+    }
+}
+
+impl Handler for BoxHandler {
+    type Future = LocalBoxFuture<'static, Response<Vec<u8>>>;
+
+    fn call(&self, request: Request<&[u8]>) -> Self::Future {
+        (**self).call(request)
+    }
+
+    fn check_continue(&self, request: &Request<()>) -> Option<Response<Vec<u8>>> {
+        (**self).check_continue(request)
+    }
+
+    fn streaming(&self, request: &Request<()>) -> Option<StreamingCall> {
+        (**self).streaming(request)
+    }
+
+    fn connection_handler(&self) -> Option<ConnectionHandler> {
+        (**self).connection_handler()
+    }
+}
+
+/// Wraps a handler in another handler, for example to add behavior before or
+/// after each request.
+pub trait Layer<H: Handler> {
+    type Handler: Handler;
+
+    fn layer(&self, inner: H) -> Self::Handler;
+}
+
+/// Hooks that run around a handler. This is the simplest way to write a
+/// `Layer`: use `handler.with(middleware)` to apply it.
+///
+/// The hooks also run around requests whose bodies the handler streams (see
+/// `Handler::streaming`), but only see their heads: `before` is passed an
+/// empty body, and a body set by `after` is ignored.
+pub trait Middleware: 'static + Send + Sync {
+    /// Runs before the handler. Returning a response skips the handler (and
+    /// any layers inside this one) and responds with it instead.
+    fn before(&self, request: &mut Request<&[u8]>) -> Option<Response<Vec<u8>>> {
+        let _ = request;
+        None
+    }
+
+    /// Runs on the response, including one returned by `before`.
+    fn after(&self, response: &mut Response<Vec<u8>>) {
+        let _ = response;
+    }
+}
+
+/// `Layer` that runs a `Middleware`'s hooks around a handler.
+pub struct MiddlewareLayer<M>(Arc<M>);
+
+impl<M: Middleware> MiddlewareLayer<M> {
+    pub fn new(middleware: M) -> MiddlewareLayer<M> {
+        MiddlewareLayer(Arc::new(middleware))
+    }
+}
+
+impl<M: Middleware, H: Handler> Layer<H> for MiddlewareLayer<M> {
+    type Handler = WithMiddleware<M, H>;
+
+    fn layer(&self, inner: H) -> WithMiddleware<M, H> {
+        WithMiddleware {
+            middleware: self.0.clone(),
+            inner,
+        }
+    }
+}
+
+/// A handler wrapped by a `Middleware`.
+pub struct WithMiddleware<M, H> {
+    middleware: Arc<M>,
+    inner: H,
+}
+
+impl<M: Middleware, H: Handler> Handler for WithMiddleware<M, H> {
+    type Future = LocalBoxFuture<'static, Response<Vec<u8>>>;
+
+    fn call(&self, request: Request<&[u8]>) -> Self::Future {
+        with_hooks(self.middleware.clone(), request, |request| {
+            self.inner.call(request)
+        })
+    }
+
+    // The middleware only sees complete requests, so this is left to the
+    // inner handler, but `after` still runs on an early response
+    fn check_continue(&self, request: &Request<()>) -> Option<Response<Vec<u8>>> {
+        let mut response = self.inner.check_continue(request)?;
+        self.middleware.after(&mut response);
+        Some(response)
+    }
+
+    // The hooks only see the heads of streamed requests and responses
+    fn streaming(&self, request: &Request<()>) -> Option<StreamingCall> {
+        let call = self.inner.streaming(request)?;
+        let middleware = self.middleware.clone();
+        Some(Box::new(move |request: Request<RequestBody>| {
+            let (parts, request_body) = request.into_parts();
+            let mut head = Request::from_parts(parts, &[][..]);
+            if let Some(mut response) = middleware.before(&mut head) {
+                middleware.after(&mut response);
+                return async move { response.map(body::full) }.boxed_local();
+            }
+            let (parts, _) = head.into_parts();
+            let response = call(Request::from_parts(parts, request_body));
+            async move {
+                let (parts, response_body) = response.await.into_parts();
+                let mut head = Response::from_parts(parts, Vec::new());
+                middleware.after(&mut head);
+                let (parts, _) = head.into_parts();
+                Response::from_parts(parts, response_body)
+            }
+            .boxed_local()
+        }))
+    }
+
+    fn connection_handler(&self) -> Option<ConnectionHandler> {
+        let mut handler = self.inner.connection_handler()?;
+        let middleware = self.middleware.clone();
+        Some(Box::new(move |request| {
+            with_hooks(middleware.clone(), request, &mut handler)
+        }))
+    }
+}
+
+// Runs the middleware's hooks around `call`
+fn with_hooks<M, F, R>(
+    middleware: Arc<M>,
+    mut request: Request<&[u8]>,
+    call: F,
+) -> LocalBoxFuture<'static, Response<Vec<u8>>>
+where
+    M: Middleware,
+    F: FnOnce(Request<&[u8]>) -> R,
+    R: Future<Output = Response<Vec<u8>>> + 'static,
+{
+    match middleware.before(&mut request) {
+        Some(mut response) => {
+            middleware.after(&mut response);
+            async move { response }.boxed_local()
+        }
+        None => {
+            let response = call(request);
+            async move {
+                let mut response = response.await;
+                middleware.after(&mut response);
+                response
+            }
+            .boxed_local()
+        }
+    }
+}
+
+/// Adds methods for wrapping handlers to every `Handler`.
+pub trait HandlerExt: Handler + Sized {
+    /// Wraps this handler in `layer`. Layers applied later run first.
+    fn layer<L: Layer<Self>>(self, layer: L) -> L::Handler {
+        layer.layer(self)
+    }
+
+    /// Wraps this handler in the hooks of `middleware`.
+    fn with<M: Middleware>(self, middleware: M) -> WithMiddleware<M, Self> {
+        MiddlewareLayer::new(middleware).layer(self)
+    }
+
+    /// Erases the type of this handler.
+    fn boxed(self) -> BoxHandler {
+        Box::new(Boxed(self))
+    }
+}
+
+impl<H: Handler> HandlerExt for H {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::BodySource;
+    use futures::executor::block_on;
+    use futures::future;
+    use futures::stream::TryStreamExt;
+    use http::{HeaderValue, StatusCode};
+
+    // Numbers the requests on each connection, and streams request bodies
+    // back as the response
+    struct Numbered;
+
+    impl Handler for Numbered {
+        type Future = future::Ready<Response<Vec<u8>>>;
+
+        fn call(&self, _request: Request<&[u8]>) -> Self::Future {
+            future::ready(Response::new(b"call".to_vec()))
+        }
+
+        fn streaming(&self, _request: &Request<()>) -> Option<StreamingCall> {
+            Some(Box::new(|request: Request<RequestBody>| {
+                let body = request.into_body().into_stream();
+                async move { Response::new(body) }.boxed_local()
+            }))
+        }
+
+        fn connection_handler(&self) -> Option<ConnectionHandler> {
+            let mut requests = 0;
+            Some(Box::new(move |_request| {
+                requests += 1;
+                let body = requests.to_string().into_bytes();
+                async move { Response::new(body) }.boxed_local()
+            }))
+        }
+    }
+
+    // Forbids /blocked, and tags every response
+    struct Tag;
+
+    impl Middleware for Tag {
+        fn before(&self, request: &mut Request<&[u8]>) -> Option<Response<Vec<u8>>> {
+            if request.uri().path() != "/blocked" {
+                return None;
+            }
+            let mut response = Response::new(Vec::new());
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            Some(response)
+        }
+
+        fn after(&self, response: &mut Response<Vec<u8>>) {
+            let tag = HeaderValue::from_static("yes");
+            response.headers_mut().insert("x-tag", tag);
+        }
+    }
+
+    fn request(uri: &str) -> Request<&'static [u8]> {
+        Request::get(uri).body(&[][..]).unwrap()
+    }
+
+    #[test]
+    fn middleware_runs_around_connection_handlers() {
+        let handler = Numbered.with(Tag);
+        let mut connection = handler.connection_handler().unwrap();
+
+        let response = block_on(connection(request("/")));
+        assert_eq!(response.headers()["x-tag"], "yes");
+        assert_eq!(response.body(), b"1");
+
+        let response = block_on(connection(request("/blocked")));
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.headers()["x-tag"], "yes");
+
+        // The blocked request never reached the connection's handler
+        assert_eq!(block_on(connection(request("/"))).body(), b"2");
+    }
+
+    #[test]
+    fn middleware_runs_around_streamed_requests() {
+        let handler = Numbered.with(Tag);
+        let stream = |uri| {
+            let head = Request::post(uri).body(()).unwrap();
+            let call = handler.streaming(&head).unwrap();
+            let body = RequestBody::new(b"hello".to_vec(), BodySource::new(-1, None, 0));
+            let response = block_on(call(head.map(|()| body)));
+            let (parts, body) = response.into_parts();
+            (parts, block_on(body.try_concat()).unwrap())
+        };
+
+        let (parts, body) = stream("/");
+        assert_eq!(parts.headers["x-tag"], "yes");
+        assert_eq!(body, b"hello");
+
+        let (parts, body) = stream("/blocked");
+        assert_eq!(parts.status, StatusCode::FORBIDDEN);
+        assert_eq!(parts.headers["x-tag"], "yes");
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn boxed_handlers_keep_their_connection_handlers() {
+        let handler = Numbered.with(Tag).boxed();
+        let mut connection = handler.connection_handler().unwrap();
+        assert_eq!(block_on(connection(request("/"))).body(), b"1");
+        assert_eq!(block_on(connection(request("/"))).body(), b"2");
+    }
+}
@@ -0,0 +1,909 @@
+use crate::body::BodyStream;
+use crate::net::{with_deadline, TcpStream};
+use crate::time::timeout;
+use futures::future::Future;
+use futures::stream::{self, StreamExt};
+use http::header::{CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version};
+use httparse::{Response as ParseResponse, Status, EMPTY_HEADER};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{debug, trace};
+
+const BUF_SIZE: usize = 4096;
+const MAX_HEADERS: usize = 64;
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_IDLE_PER_HOST: usize = 8;
+const DEFAULT_MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid HTTP response: {0}")]
+    Parse(#[from] httparse::Error),
+    #[error("Invalid HTTP response: {0}")]
+    InvalidResponse(&'static str),
+    #[error("Invalid HTTP request: {0}")]
+    InvalidRequest(&'static str),
+    #[error("Error building HTTP response: {0}")]
+    Http(#[from] http::Error),
+    #[error("Request URI must be absolute and use the http scheme: {0}")]
+    InvalidUri(Uri),
+    #[error("Request timed out")]
+    Timeout,
+    #[error("Response body exceeded the limit of {0} bytes")]
+    ResponseTooLarge(usize),
+}
+
+// Connections are pooled by the host and port they are connected to
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    host: String,
+    port: u16,
+}
+
+impl PoolKey {
+    fn from_uri(uri: &Uri) -> Result<PoolKey, ClientError> {
+        match (uri.scheme_str(), uri.host()) {
+            (Some("http"), Some(host)) => Ok(PoolKey {
+                // IPv6 literals are bracketed in URIs but not when resolving
+                host: host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                port: uri.port_u16().unwrap_or(80),
+            }),
+            _ => Err(ClientError::InvalidUri(uri.clone())),
+        }
+    }
+}
+
+// How the end of a response body is determined
+#[derive(Clone, Copy)]
+enum Framing {
+    Empty,
+    Length(usize),
+    Chunked,
+    Close,
+}
+
+/// HTTP/1.1 client that runs on the io-uring runtime.
+///
+/// Connections are kept alive and reused for later requests to the same host.
+/// The client is cheap to clone and clones share the same connection pool.
+#[derive(Clone)]
+pub struct HttpClient {
+    pool: Arc<Mutex<HashMap<PoolKey, Vec<TcpStream>>>>,
+    max_idle_per_host: usize,
+    max_response_size: usize,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+}
+
+impl HttpClient {
+    pub fn new() -> HttpClient {
+        HttpClient {
+            pool: Arc::new(Mutex::new(HashMap::new())),
+            max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            connect_timeout: None,
+            timeout: None,
+        }
+    }
+
+    /// Limits how long establishing a new connection may take.
+    pub fn connect_timeout(mut self, timeout: Duration) -> HttpClient {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits how long sending a request and reading its whole response may
+    /// take, not including the time spent connecting.
+    pub fn timeout(mut self, timeout: Duration) -> HttpClient {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how many idle connections are kept open for each host.
+    pub fn max_idle_per_host(mut self, max: usize) -> HttpClient {
+        self.max_idle_per_host = max;
+        self
+    }
+
+    /// Sets the largest response body that will be read (16 MiB by
+    /// default). Larger responses fail with `ClientError::ResponseTooLarge`.
+    pub fn max_response_size(mut self, max: usize) -> HttpClient {
+        self.max_response_size = max;
+        self
+    }
+
+    // The limit set by `timeout`
+    pub(crate) fn request_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub async fn get(&self, uri: &str) -> Result<Response<Vec<u8>>, ClientError> {
+        let request = Request::get(uri).body(Vec::new())?;
+        self.send(request).await
+    }
+
+    /// Sends the request and reads the whole response.
+    ///
+    /// The request URI must be absolute (for example `http://localhost:3000/path`).
+    /// Host names are resolved with `ToSocketAddrs`, which blocks the thread.
+    pub async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Vec<u8>>, ClientError> {
+        let key = PoolKey::from_uri(request.uri())?;
+        let request_buf = serialize_request(&request);
+        let is_head = request.method() == Method::HEAD;
+
+        if let Some(stream) = self.checkout(&key) {
+            trace!("reusing pooled connection to {}:{}", key.host, key.port);
+            match self.exchange(&key, stream, &request_buf, is_head).await {
+                // The server may have closed the idle connection before it
+                // saw our request, in which case it is safe to try again
+                // with a new connection if the method is idempotent
+                Err(ClientError::Io(err))
+                    if is_idempotent(request.method()) && is_stale_connection(&err) =>
+                {
+                    debug!("pooled connection was closed, retrying: {}", err);
+                }
+                result => return result,
+            }
+        }
+
+        let stream = self.connect(&key).await?;
+        self.exchange(&key, stream, &request_buf, is_head).await
+    }
+
+    /// Sends the request and returns once the response head has arrived,
+    /// without holding either body in memory whole.
+    ///
+    /// The request body is sent as it's produced: with the request's
+    /// Content-Length if it has one, chunked if it has `Transfer-Encoding:
+    /// chunked`, and not at all otherwise. The response body is read as the
+    /// returned stream is polled, and the connection goes back to the pool
+    /// once it has all been read. The `timeout` applies to getting the
+    /// response head, and then to each read of the body, while
+    /// `max_response_size` doesn't apply.
+    pub async fn send_streaming(
+        &self,
+        request: Request<BodyStream>,
+    ) -> Result<Response<BodyStream>, ClientError> {
+        let key = PoolKey::from_uri(request.uri())?;
+        let framing = request_framing(&request)?;
+        let mut head = serialize_head(&request);
+        head.extend_from_slice(b"\r\n");
+        let is_head = request.method() == Method::HEAD;
+        // A body that has been sent can't be sent again
+        let can_retry = is_idempotent(request.method()) && matches!(framing, RequestFraming::Empty);
+        let mut body = request.into_body();
+
+        if let Some(stream) = self.checkout(&key) {
+            trace!("reusing pooled connection to {}:{}", key.host, key.port);
+            match self
+                .exchange_streaming(&key, stream, &head, &mut body, framing, is_head)
+                .await
+            {
+                Err(ClientError::Io(err)) if can_retry && is_stale_connection(&err) => {
+                    debug!("pooled connection was closed, retrying: {}", err);
+                }
+                result => return result,
+            }
+        }
+
+        let stream = self.connect(&key).await?;
+        self.exchange_streaming(&key, stream, &head, &mut body, framing, is_head)
+            .await
+    }
+
+    fn checkout(&self, key: &PoolKey) -> Option<TcpStream> {
+        let mut pool = self.pool.lock().unwrap();
+        let idle = pool.get_mut(key)?;
+        while let Some(stream) = idle.pop() {
+            if is_open(&stream) {
+                return Some(stream);
+            }
+            trace!("discarding closed pooled connection");
+        }
+        None
+    }
+
+    fn checkin(&self, key: &PoolKey, stream: TcpStream) {
+        let mut pool = self.pool.lock().unwrap();
+        let idle = pool.entry(key.clone()).or_default();
+        if idle.len() < self.max_idle_per_host {
+            idle.push(stream);
+        }
+    }
+
+    async fn connect(&self, key: &PoolKey) -> Result<TcpStream, ClientError> {
+        trace!("connecting to {}:{}", key.host, key.port);
+        let connect = TcpStream::connect((key.host.as_str(), key.port));
+        let connect_timeout = match self.connect_timeout {
+            Some(connect_timeout) => connect_timeout,
+            None => return Ok(connect.await?),
+        };
+
+        // Connect owns everything the kernel reads, so it is safe to drop
+        // it if the timer fires first
+        match timeout(connect_timeout, connect).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(ClientError::Timeout),
+        }
+    }
+
+    async fn exchange(
+        &self,
+        key: &PoolKey,
+        mut stream: TcpStream,
+        request_buf: &[u8],
+        is_head: bool,
+    ) -> Result<Response<Vec<u8>>, ClientError> {
+        let fd = stream.as_raw_fd();
+        let exchange = async {
+            stream.write_all(request_buf).await?;
+            read_response(&mut stream, is_head, self.max_response_size).await
+        };
+
+        let (response, keep_alive) = self.within_timeout(fd, exchange).await?;
+        if keep_alive {
+            self.checkin(key, stream);
+        }
+        Ok(response)
+    }
+
+    async fn exchange_streaming(
+        &self,
+        key: &PoolKey,
+        mut stream: TcpStream,
+        head: &[u8],
+        body: &mut BodyStream,
+        framing: RequestFraming,
+        is_head: bool,
+    ) -> Result<Response<BodyStream>, ClientError> {
+        let fd = stream.as_raw_fd();
+        let mut buf = Vec::with_capacity(BUF_SIZE);
+        let exchange = async {
+            stream.write_all(head).await?;
+            send_body(&mut stream, body, framing).await?;
+            read_head(&mut stream, &mut buf, is_head).await
+        };
+
+        let (response, framing) = self.within_timeout(fd, exchange).await?;
+        let body = ResponseBody {
+            client: self.clone(),
+            key: key.clone(),
+            keep_alive: can_reuse(&response, framing),
+            stream,
+            buf,
+            state: BodyState::new(framing),
+        };
+        Ok(response.map(|()| body.into_stream()))
+    }
+
+    // Applies the request timeout to an operation on the connection `fd`
+    async fn within_timeout<F, T>(&self, fd: RawFd, operation: F) -> Result<T, ClientError>
+    where
+        F: Future<Output = Result<T, ClientError>>,
+    {
+        match self.timeout {
+            Some(timeout) => with_deadline(fd, timeout, operation)
+                .await
+                .unwrap_or(Err(ClientError::Timeout)),
+            None => operation.await,
+        }
+    }
+}
+
+impl Default for HttpClient {
+    fn default() -> HttpClient {
+        HttpClient::new()
+    }
+}
+
+// Checks whether an idle connection is still usable. The server should not
+// have sent anything, so a readable socket means it has been closed (or the
+// server misbehaved) and it shouldn't be reused.
+fn is_open(stream: &TcpStream) -> bool {
+    let mut byte = 0u8;
+    let n = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            &mut byte as *mut u8 as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    n < 0 && io::Error::last_os_error().kind() == ErrorKind::WouldBlock
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+fn is_stale_connection(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+    )
+}
+
+fn serialize_request(request: &Request<Vec<u8>>) -> Vec<u8> {
+    let mut buf = serialize_head(request);
+    let headers = request.headers();
+    let body = request.body();
+    if is_chunked(headers) {
+        // The whole body is already in memory, so send it as a single chunk
+        buf.extend_from_slice(b"\r\n");
+        if !body.is_empty() {
+            buf.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            buf.extend_from_slice(body);
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"0\r\n\r\n");
+        return buf;
+    }
+
+    let sends_body = !body.is_empty()
+        || matches!(
+            *request.method(),
+            Method::POST | Method::PUT | Method::PATCH
+        );
+    if sends_body && !headers.contains_key(CONTENT_LENGTH) {
+        buf.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    }
+    buf.extend_from_slice(b"\r\n");
+    buf.extend_from_slice(body);
+    buf
+}
+
+// Serializes the request line and headers, but not the empty line that ends
+// them, so that framing headers can still be added
+fn serialize_head<B>(request: &Request<B>) -> Vec<u8> {
+    let uri = request.uri();
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut buf = format!("{} {} HTTP/1.1\r\n", request.method(), path).into_bytes();
+
+    let headers = request.headers();
+    if !headers.contains_key(HOST) {
+        if let Some(authority) = uri.authority() {
+            buf.extend_from_slice(format!("Host: {}\r\n", authority).as_bytes());
+        }
+    }
+    for (name, value) in headers.iter() {
+        buf.extend_from_slice(name.as_str().as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(value.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+// How a streamed request body is sent
+#[derive(Clone, Copy)]
+enum RequestFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+}
+
+fn request_framing<B>(request: &Request<B>) -> Result<RequestFraming, ClientError> {
+    if is_chunked(request.headers()) {
+        return Ok(RequestFraming::Chunked);
+    }
+    match request.headers().get(CONTENT_LENGTH) {
+        Some(value) => parse_content_length(value)
+            .map(RequestFraming::Length)
+            .ok_or(ClientError::InvalidRequest("invalid Content-Length header")),
+        None => Ok(RequestFraming::Empty),
+    }
+}
+
+// Sends a streamed request body as it's produced
+async fn send_body(
+    stream: &mut TcpStream,
+    body: &mut BodyStream,
+    framing: RequestFraming,
+) -> Result<(), ClientError> {
+    if let RequestFraming::Empty = framing {
+        return Ok(());
+    }
+    let mut sent = 0;
+    while let Some(piece) = body.next().await {
+        let piece = piece?;
+        sent += piece.len();
+        match framing {
+            RequestFraming::Length(length) if sent > length => {
+                return Err(ClientError::InvalidRequest(
+                    "request body is longer than its Content-Length",
+                ));
+            }
+            RequestFraming::Chunked if piece.is_empty() => {}
+            RequestFraming::Chunked => {
+                let mut chunk = format!("{:x}\r\n", piece.len()).into_bytes();
+                chunk.extend_from_slice(&piece);
+                chunk.extend_from_slice(b"\r\n");
+                stream.write_all(&chunk).await?;
+            }
+            _ => stream.write_all(&piece).await?,
+        }
+    }
+    match framing {
+        RequestFraming::Length(length) if sent < length => Err(ClientError::InvalidRequest(
+            "request body is shorter than its Content-Length",
+        )),
+        RequestFraming::Chunked => Ok(stream.write_all(b"0\r\n\r\n").await?),
+        _ => Ok(()),
+    }
+}
+
+fn is_chunked(headers: &HeaderMap) -> bool {
+    // Chunked must be the last transfer coding if it is used at all
+    headers
+        .get_all(TRANSFER_ENCODING)
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
+}
+
+fn has_connection_option(headers: &HeaderMap, option: &str) -> bool {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case(option))
+}
+
+// Reads more data from the stream onto the end of `buf`, returning the number
+// of bytes read
+async fn fill(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<usize, io::Error> {
+    let start = buf.len();
+    buf.resize(start + BUF_SIZE, 0);
+    let result = stream.read(&mut buf[start..]).await;
+    buf.truncate(start + *result.as_ref().unwrap_or(&0));
+    result
+}
+
+// Like `fill`, but treats the connection closing as an error
+async fn fill_or_eof(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<(), ClientError> {
+    match fill(stream, buf).await? {
+        0 => Err(ClientError::InvalidResponse(
+            "connection closed before the response was complete",
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Reads a response from the stream and returns it along with whether the
+// connection can be reused for another request
+async fn read_response(
+    stream: &mut TcpStream,
+    is_head: bool,
+    max_body_size: usize,
+) -> Result<(Response<Vec<u8>>, bool), ClientError> {
+    let mut buf: Vec<u8> = Vec::with_capacity(BUF_SIZE);
+    let (response, framing) = read_head(stream, &mut buf, is_head).await?;
+
+    let mut body = match framing {
+        Framing::Length(length) if length > max_body_size => {
+            return Err(ClientError::ResponseTooLarge(max_body_size));
+        }
+        Framing::Length(length) => Vec::with_capacity(length),
+        _ => Vec::new(),
+    };
+    let mut state = BodyState::new(framing);
+    while let Some(piece) = state.read(stream, &mut buf).await? {
+        if piece.len() > max_body_size - body.len() {
+            return Err(ClientError::ResponseTooLarge(max_body_size));
+        }
+        body.extend_from_slice(&piece);
+    }
+
+    // Only reuse the connection if we know exactly where the response ended
+    let keep_alive = can_reuse(&response, framing) && buf.is_empty();
+    Ok((response.map(|()| body), keep_alive))
+}
+
+// Reads the head of a response into `buf`, skipping any informational
+// responses, and leaves whatever follows it in `buf`
+async fn read_head(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    is_head: bool,
+) -> Result<(Response<()>, Framing), ClientError> {
+    loop {
+        let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = ParseResponse::new(&mut headers);
+
+        match parsed.parse(buf)? {
+            Status::Complete(head_len) => {
+                let response = convert_http_response(parsed)?;
+                buf.drain(..head_len);
+
+                // Skip over informational responses like 100 Continue
+                if response.status().is_informational()
+                    && response.status() != StatusCode::SWITCHING_PROTOCOLS
+                {
+                    trace!("skipping {} response", response.status());
+                    continue;
+                }
+
+                let framing = response_framing(&response, is_head)?;
+                return Ok((response, framing));
+            }
+            Status::Partial if buf.len() > MAX_HEAD_SIZE => {
+                return Err(ClientError::InvalidResponse("response head is too large"));
+            }
+            Status::Partial => {
+                trace!("got partial HTTP response");
+                if fill(stream, buf).await? == 0 {
+                    return Err(if buf.is_empty() {
+                        io::Error::from(ErrorKind::UnexpectedEof).into()
+                    } else {
+                        ClientError::InvalidResponse("connection closed in the response head")
+                    });
+                }
+            }
+        }
+    }
+}
+
+// Whether the connection can be used for another request once the response
+// body has been read
+fn can_reuse(response: &Response<()>, framing: Framing) -> bool {
+    let keep_alive = match response.version() {
+        Version::HTTP_11 => !has_connection_option(response.headers(), "close"),
+        _ => has_connection_option(response.headers(), "keep-alive"),
+    };
+    keep_alive && !matches!(framing, Framing::Close)
+}
+
+fn convert_http_response(parsed: ParseResponse) -> Result<Response<()>, ClientError> {
+    let version = match parsed.version {
+        Some(0) => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    };
+    let builder = Response::builder()
+        .status(parsed.code.unwrap_or_default())
+        .version(version);
+    let builder = parsed.headers.iter().fold(builder, |builder, header| {
+        builder.header(header.name, header.value)
+    });
+    Ok(builder.body(())?)
+}
+
+fn response_framing(response: &Response<()>, is_head: bool) -> Result<Framing, ClientError> {
+    let status = response.status();
+    if is_head || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+        return Ok(Framing::Empty);
+    }
+    if response.headers().contains_key(TRANSFER_ENCODING) {
+        return Ok(if is_chunked(response.headers()) {
+            Framing::Chunked
+        } else {
+            Framing::Close
+        });
+    }
+    match response.headers().get(CONTENT_LENGTH) {
+        Some(value) => {
+            parse_content_length(value)
+                .map(Framing::Length)
+                .ok_or(ClientError::InvalidResponse(
+                    "invalid Content-Length header",
+                ))
+        }
+        None => Ok(Framing::Close),
+    }
+}
+
+// Only digits are allowed, unlike `usize::from_str` which also accepts a sign
+fn parse_content_length(value: &HeaderValue) -> Option<usize> {
+    let value = value.to_str().ok()?.trim();
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+// Where a response body is up to, so it can be read a piece at a time
+#[derive(Clone, Copy)]
+enum BodyState {
+    // The number of bytes left in the body
+    Length(usize),
+    // At the line with the next chunk's size
+    ChunkSize,
+    // The number of bytes left in the chunk
+    ChunkData(usize),
+    // At the CRLF after a chunk
+    ChunkEnd,
+    Trailers,
+    UntilClose,
+    Done,
+}
+
+impl BodyState {
+    fn new(framing: Framing) -> BodyState {
+        match framing {
+            Framing::Empty => BodyState::Done,
+            Framing::Length(length) => BodyState::Length(length),
+            Framing::Chunked => BodyState::ChunkSize,
+            Framing::Close => BodyState::UntilClose,
+        }
+    }
+
+    // Reads the next piece of the body, consuming it (and any chunked framing
+    // and trailers) from `buf`, and returns None at the end of the body
+    async fn read(
+        &mut self,
+        stream: &mut TcpStream,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, ClientError> {
+        loop {
+            match *self {
+                BodyState::Done => return Ok(None),
+                BodyState::Length(0) => *self = BodyState::Done,
+                BodyState::ChunkData(0) => *self = BodyState::ChunkEnd,
+                BodyState::Length(left) | BodyState::ChunkData(left) => {
+                    if buf.is_empty() {
+                        fill_or_eof(stream, buf).await?;
+                    }
+                    let piece: Vec<u8> = buf.drain(..left.min(buf.len())).collect();
+                    let left = left - piece.len();
+                    *self = match *self {
+                        BodyState::Length(_) => BodyState::Length(left),
+                        _ => BodyState::ChunkData(left),
+                    };
+                    return Ok(Some(piece));
+                }
+                BodyState::ChunkSize => match httparse::parse_chunk_size(buf) {
+                    Ok(Status::Complete((start, size))) => {
+                        buf.drain(..start);
+                        // The size comes from the server and can be anything
+                        // up to u64::MAX, but it's only ever counted down
+                        *self = match usize::try_from(size) {
+                            Ok(0) => BodyState::Trailers,
+                            Ok(size) => BodyState::ChunkData(size),
+                            Err(_) => {
+                                return Err(ClientError::InvalidResponse("chunk is too large"))
+                            }
+                        };
+                    }
+                    Ok(Status::Partial) if buf.len() > MAX_HEAD_SIZE => {
+                        return Err(ClientError::InvalidResponse("chunk size line is too long"));
+                    }
+                    Ok(Status::Partial) => fill_or_eof(stream, buf).await?,
+                    Err(_) => return Err(ClientError::InvalidResponse("invalid chunk size")),
+                },
+                // Each chunk is followed by a CRLF
+                BodyState::ChunkEnd => {
+                    while buf.len() < 2 {
+                        fill_or_eof(stream, buf).await?;
+                    }
+                    if &buf[..2] != b"\r\n" {
+                        return Err(ClientError::InvalidResponse("chunk is missing its CRLF"));
+                    }
+                    buf.drain(..2);
+                    *self = BodyState::ChunkSize;
+                }
+                // Trailers are discarded, and end with an empty line
+                BodyState::Trailers => match buf.windows(2).position(|window| window == b"\r\n") {
+                    Some(0) => {
+                        buf.drain(..2);
+                        *self = BodyState::Done;
+                    }
+                    Some(line_end) => {
+                        buf.drain(..line_end + 2);
+                    }
+                    None if buf.len() > MAX_HEAD_SIZE => {
+                        return Err(ClientError::InvalidResponse("trailers are too large"));
+                    }
+                    None => fill_or_eof(stream, buf).await?,
+                },
+                BodyState::UntilClose => {
+                    if !buf.is_empty() {
+                        return Ok(Some(mem::take(buf)));
+                    }
+                    if fill(stream, buf).await? == 0 {
+                        *self = BodyState::Done;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The body of a response from `send_streaming`, which owns the connection
+// until it has all been read
+struct ResponseBody {
+    client: HttpClient,
+    key: PoolKey,
+    keep_alive: bool,
+    stream: TcpStream,
+    buf: Vec<u8>,
+    state: BodyState,
+}
+
+impl ResponseBody {
+    fn into_stream(self) -> BodyStream {
+        stream::unfold(Some(self), |body| async move {
+            let mut body = body?;
+            let fd = body.stream.as_raw_fd();
+            let read = body.state.read(&mut body.stream, &mut body.buf);
+            match body.client.within_timeout(fd, read).await {
+                Ok(Some(piece)) => Some((Ok(piece), Some(body))),
+                Ok(None) => {
+                    if body.keep_alive && body.buf.is_empty() {
+                        body.client.checkin(&body.key, body.stream);
+                    }
+                    None
+                }
+                // The connection is dropped, since where the response ends
+                // isn't known anymore
+                Err(ClientError::Io(err)) => Some((Err(err), None)),
+                Err(err) => Some((Err(io::Error::new(ErrorKind::InvalidData, err)), None)),
+            }
+        })
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Runtime;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const HEAD: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    // Reads a response that a server sends in `pieces`, pausing between them
+    // so that each one arrives in its own read, and then closes the connection
+    fn read(
+        pieces: &[&[u8]],
+        max_body_size: usize,
+    ) -> Result<(Response<Vec<u8>>, bool), ClientError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pieces: Vec<Vec<u8>> = pieces.iter().map(|piece| piece.to_vec()).collect();
+        let server = thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            for piece in pieces {
+                // The client stops reading once it has the response or an error
+                if stream.write_all(&piece).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+        });
+        let mut stream = TcpStream::from(listener.accept().unwrap().0);
+        let result = Runtime::new().block_on(read_response(&mut stream, false, max_body_size));
+        drop(stream);
+        server.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn chunked_body_is_decoded_across_split_reads() {
+        let (response, keep_alive) = read(
+            &[
+                HEAD,
+                b"5\r\nhel",
+                b"lo\r",
+                b"\n1",
+                b"0;name=value\r\n0123456789",
+                b"abcdef\r\n0\r\n",
+                b"x-trailer: 1\r\n",
+                b"\r\n",
+            ],
+            DEFAULT_MAX_RESPONSE_SIZE,
+        )
+        .unwrap();
+        assert_eq!(response.body(), b"hello0123456789abcdef");
+        assert!(keep_alive);
+    }
+
+    #[test]
+    fn chunk_without_crlf_is_rejected() {
+        let result = read(
+            &[HEAD, b"5\r\nhelloX\r\n0\r\n\r\n"],
+            DEFAULT_MAX_RESPONSE_SIZE,
+        );
+        assert!(matches!(
+            result,
+            Err(ClientError::InvalidResponse("chunk is missing its CRLF"))
+        ));
+    }
+
+    #[test]
+    fn overflowing_chunk_size_is_rejected() {
+        let result = read(
+            &[HEAD, b"1FFFFFFFFFFFFFFFF\r\nhello\r\n0\r\n\r\n"],
+            DEFAULT_MAX_RESPONSE_SIZE,
+        );
+        assert!(matches!(
+            result,
+            Err(ClientError::InvalidResponse("invalid chunk size"))
+        ));
+    }
+
+    #[test]
+    fn oversized_chunk_is_rejected_by_the_size_limit() {
+        let data = [b'a'; 64];
+        let result = read(&[HEAD, b"FFFFFFFF\r\n", &data, &data], 100);
+        assert!(matches!(result, Err(ClientError::ResponseTooLarge(100))));
+    }
+
+    #[test]
+    fn content_length_body_ends_at_its_length() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        let (response, keep_alive) = read(&[head, b"hel", b"lo"], 5).unwrap();
+        assert_eq!(response.body(), b"hello");
+        assert!(keep_alive);
+
+        // Anything after the body means the connection can't be reused
+        let (response, keep_alive) = read(&[head, b"helloextra"], 5).unwrap();
+        assert_eq!(response.body(), b"hello");
+        assert!(!keep_alive);
+    }
+
+    #[test]
+    fn content_length_body_cut_short_is_rejected() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n";
+        let result = read(&[head, b"hello"], DEFAULT_MAX_RESPONSE_SIZE);
+        assert!(matches!(result, Err(ClientError::InvalidResponse(_))));
+    }
+
+    #[test]
+    fn body_without_framing_ends_at_close() {
+        let head = b"HTTP/1.1 200 OK\r\n\r\n";
+        let (response, keep_alive) = read(&[head, b"hel", b"lo"], 5).unwrap();
+        assert_eq!(response.body(), b"hello");
+        assert!(!keep_alive);
+    }
+
+    #[test]
+    fn content_length_over_the_limit_is_rejected_up_front() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\n";
+        let result = read(&[head, b"hello!"], 5);
+        assert!(matches!(result, Err(ClientError::ResponseTooLarge(5))));
+    }
+
+    #[test]
+    fn body_over_the_limit_is_rejected() {
+        let head = b"HTTP/1.1 200 OK\r\n\r\n";
+        let result = read(&[head, b"hel", b"lo!"], 5);
+        assert!(matches!(result, Err(ClientError::ResponseTooLarge(5))));
+
+        let result = read(&[HEAD, b"3\r\nhel\r\n", b"3\r\nlo!\r\n0\r\n\r\n"], 5);
+        assert!(matches!(result, Err(ClientError::ResponseTooLarge(5))));
+    }
+
+    #[test]
+    fn invalid_content_length_is_rejected() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: +5\r\n\r\nhello";
+        let result = read(&[head], DEFAULT_MAX_RESPONSE_SIZE);
+        assert!(matches!(
+            result,
+            Err(ClientError::InvalidResponse(
+                "invalid Content-Length header"
+            ))
+        ));
+    }
+}
@@ -1,86 +1,472 @@
-use crate::runtime::{spawn, Runtime};
+use crate::body::{self, BodySource, BodyStream, RequestBody};
+use crate::executor::PanicHook;
+use crate::handler::{Handler, StreamingCall};
+use crate::net::{with_deadline, Connection, Listener, UnixListener};
+use crate::response::IntoResponse;
+use crate::runtime::{report_panic, spawn_local, Runtime};
 use crate::syscall::{Accept, Close, Recv, Send};
-use futures::{channel::mpsc::unbounded, future::Future, SinkExt, StreamExt};
-use http::{Request, Response, Version};
+use crate::time::sleep;
+use futures::future::Either;
+use futures::{channel::mpsc::unbounded, FutureExt, SinkExt, StreamExt};
+use http::header::{CONNECTION, CONTENT_LENGTH, DATE, EXPECT, SERVER, TRANSFER_ENCODING};
+use http::response::Parts;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Version};
 use httparse::{Request as ParseRequest, Status, EMPTY_HEADER};
-use std::io::Error;
-use std::net::{TcpListener, TcpStream};
-use std::os::unix::io::FromRawFd;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener};
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread::spawn as spawn_thread;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
 use tracing::{debug, error, span, trace, Level};
 
 const BUF_SIZE: usize = 512;
+const MAX_HEADERS: usize = 24;
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const SERVER_NAME: &str = concat!("iou-http/", env!("CARGO_PKG_VERSION"));
+// How long to wait before accepting again after an error that isn't specific
+// to one connection, like running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
-fn serialize_response(response: http::Response<Vec<u8>>) -> Vec<u8> {
-    let (parts, body) = response.into_parts();
+/// Errors that end a connection.
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid HTTP request: {0}")]
+    Parse(#[from] httparse::Error),
+    #[error("Invalid HTTP request: {0}")]
+    InvalidRequest(#[from] http::Error),
+    #[error("Invalid HTTP request: {0}")]
+    BadRequest(&'static str),
+    #[error("Unsupported HTTP version")]
+    UnsupportedVersion,
+    #[error("Unsupported transfer coding")]
+    UnsupportedTransferEncoding,
+    #[error("Timed out reading the request")]
+    Timeout,
+    #[error("Request exceeded the {0} limit")]
+    Limit(Limit),
+}
+
+/// The limit that a request exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    HeaderCount,
+    HeaderSize,
+    BodySize,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::HeaderCount => "header count",
+            Limit::HeaderSize => "header size",
+            Limit::BodySize => "body size",
+        })
+    }
+}
+
+impl ServerError {
+    // The response that tells the client why the connection is being closed.
+    // There is none for I/O errors or timeouts, because the socket can't be
+    // written to anymore
+    fn response(&self) -> Option<Response<Vec<u8>>> {
+        let status = match self {
+            ServerError::Parse(_) | ServerError::InvalidRequest(_) | ServerError::BadRequest(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ServerError::UnsupportedVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            ServerError::UnsupportedTransferEncoding => StatusCode::NOT_IMPLEMENTED,
+            ServerError::Limit(Limit::BodySize) => StatusCode::PAYLOAD_TOO_LARGE,
+            ServerError::Limit(_) => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ServerError::Io(_) | ServerError::Timeout => return None,
+        };
+        Some((status, status.canonical_reason().unwrap_or("")).into_response())
+    }
+}
+
+/// Counts the connections handled by an `HttpServer` and the errors that
+/// ended them.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    connections: AtomicU64,
+    accept_errors: AtomicU64,
+    io_errors: AtomicU64,
+    parse_errors: AtomicU64,
+    timeouts: AtomicU64,
+    limit_errors: AtomicU64,
+}
+
+impl ServerMetrics {
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// The number of times accepting a connection failed.
+    pub fn accept_errors(&self) -> u64 {
+        self.accept_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn io_errors(&self) -> u64 {
+        self.io_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn parse_errors(&self) -> u64 {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    pub fn limit_errors(&self) -> u64 {
+        self.limit_errors.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, err: &ServerError) {
+        let counter = match err {
+            ServerError::Io(_) => &self.io_errors,
+            ServerError::Parse(_)
+            | ServerError::InvalidRequest(_)
+            | ServerError::BadRequest(_)
+            | ServerError::UnsupportedVersion
+            | ServerError::UnsupportedTransferEncoding => &self.parse_errors,
+            ServerError::Timeout => &self.timeouts,
+            ServerError::Limit(_) => &self.limit_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// The head of a request, and the range of the read buffer that holds its body
+type ParsedRequest = (Request<()>, Range<usize>);
+
+// Settings that are copied into each connection task
+#[derive(Clone, Copy)]
+struct Config {
+    read_timeout: Option<Duration>,
+    max_body_size: usize,
+}
+
+// Serializes a response, framing the body as RFC 9112 section 6 requires.
+// Responses to HEAD requests get the headers that the response to a GET would
+// have had, but no body.
+fn serialize_response(response: Response<Vec<u8>>, head_request: bool) -> Vec<u8> {
+    let (mut parts, mut body) = response.into_parts();
+    let status = parts.status;
+    let headers = &mut parts.headers;
+
+    if status.is_informational() || status == StatusCode::NO_CONTENT {
+        // These can't have a body or describe one (RFC 9110 section 8.6)
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(TRANSFER_ENCODING);
+        body.clear();
+    } else if status == StatusCode::NOT_MODIFIED || head_request {
+        // These can describe the body that a GET would have gotten, so
+        // the framing headers set by the handler are kept
+        if headers.contains_key(TRANSFER_ENCODING) {
+            end_with_chunked(headers);
+        } else if status != StatusCode::NOT_MODIFIED && !headers.contains_key(CONTENT_LENGTH) {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+        body.clear();
+    } else if headers.contains_key(TRANSFER_ENCODING) {
+        headers.remove(CONTENT_LENGTH);
+        end_with_chunked(headers);
+        body = encode_chunked(&body);
+    } else {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+
+    let mut response = serialize_head(&mut parts);
+    response.extend_from_slice(&body);
+    response
+}
+
+// Serializes the status line and headers, adding the ones every response has
+fn serialize_head(parts: &mut Parts) -> Vec<u8> {
+    let headers = &mut parts.headers;
+    if !headers.contains_key(DATE) {
+        if let Ok(date) = HeaderValue::from_str(&httpdate::fmt_http_date(SystemTime::now())) {
+            headers.insert(DATE, date);
+        }
+    }
+    if !headers.contains_key(SERVER) {
+        headers.insert(SERVER, HeaderValue::from_static(SERVER_NAME));
+    }
+
+    // Only HTTP/1.x requests are accepted, so anything else is answered as HTTP/1.1
+    let version = match parts.version {
+        Version::HTTP_10 => "HTTP/1.0",
+        _ => "HTTP/1.1",
+    };
     // TODO serialize the HTTP response with less copying
-    let mut response = format!("HTTP/1.1 {}\r\n", parts.status);
-    let mut has_content_length: bool = false;
-    for (name, value) in parts.headers.iter() {
-        if let Ok(value) = value.to_str() {
-            has_content_length = value.to_ascii_lowercase() == "content-length";
-            response.push_str(format!("{}: {}\r\n", name, value).as_str());
+    let mut head = format!(
+        "{} {} {}\r\n",
+        version,
+        parts.status.as_u16(),
+        parts.status.canonical_reason().unwrap_or("")
+    )
+    .into_bytes();
+    // Header values are written as they are, since they don't have to be UTF-8
+    for (name, value) in headers.iter() {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+// How a streamed response body is framed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamFraming {
+    None,
+    Length(usize),
+    Chunked,
+    Close,
+}
+
+// Like `serialize_response`, but for a body whose length usually isn't known
+// when the head is sent. Without a Content-Length the body is chunked, or
+// ended by closing the connection for HTTP/1.0 clients.
+fn stream_framing<T>(
+    response: &mut Response<T>,
+    version: Version,
+    head_request: bool,
+) -> StreamFraming {
+    let status = response.status();
+    let headers = response.headers_mut();
+
+    if status.is_informational() || status == StatusCode::NO_CONTENT {
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(TRANSFER_ENCODING);
+        return StreamFraming::None;
+    }
+    if status == StatusCode::NOT_MODIFIED || head_request {
+        if headers.contains_key(TRANSFER_ENCODING) {
+            end_with_chunked(headers);
         }
+        return StreamFraming::None;
+    }
+    // Transfer-Encoding is removed for HTTP/1.0 clients
+    if version == Version::HTTP_11 && headers.contains_key(TRANSFER_ENCODING) {
+        headers.remove(CONTENT_LENGTH);
+        end_with_chunked(headers);
+        return StreamFraming::Chunked;
     }
-    if !has_content_length {
-        response.push_str(format!("Content-Length: {}\r\n", body.len()).as_str());
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| parse_content_length(value.as_bytes()));
+    if let Some(length) = content_length {
+        return StreamFraming::Length(length);
     }
-    response.push_str("\r\n");
+    headers.remove(CONTENT_LENGTH);
+    if version == Version::HTTP_11 {
+        end_with_chunked(headers);
+        StreamFraming::Chunked
+    } else {
+        StreamFraming::Close
+    }
+}
 
-    let mut response = response.into_bytes();
-    response.extend_from_slice(&body);
+// Sends a streamed response body as it's produced. If the body ends early,
+// the connection has to be closed, which for a chunked body tells the client
+// that it's incomplete.
+async fn send_body_stream<C: AsRawFd>(
+    stream: &mut C,
+    mut body: BodyStream,
+    framing: StreamFraming,
+) -> Result<(), ServerError> {
+    if framing == StreamFraming::None {
+        return Ok(());
+    }
+    let mut sent = 0;
+    while let Some(piece) = body.next().await {
+        let piece = piece?;
+        sent += piece.len();
+        match framing {
+            StreamFraming::Length(length) if sent > length => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "response body is longer than its Content-Length",
+                )
+                .into());
+            }
+            StreamFraming::Chunked if piece.is_empty() => {}
+            StreamFraming::Chunked => {
+                let mut chunk = format!("{:X}\r\n", piece.len()).into_bytes();
+                chunk.extend_from_slice(&piece);
+                chunk.extend_from_slice(b"\r\n");
+                send_all(stream, &chunk).await?;
+            }
+            _ => send_all(stream, &piece).await?,
+        }
+    }
+    match framing {
+        StreamFraming::Length(length) if sent < length => Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "response body is shorter than its Content-Length",
+        )
+        .into()),
+        StreamFraming::Chunked => Ok(send_all(stream, b"0\r\n\r\n").await?),
+        _ => Ok(()),
+    }
+}
 
-    response
+// Chunked has to be the last transfer coding (RFC 9112 section 6.1)
+fn end_with_chunked(headers: &mut HeaderMap) {
+    let mut codings: Vec<String> = headers
+        .get_all(TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_string())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    if !codings
+        .last()
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+    {
+        codings.push("chunked".to_string());
+    }
+    if let Ok(value) = HeaderValue::from_str(&codings.join(", ")) {
+        headers.insert(TRANSFER_ENCODING, value);
+    }
+}
+
+// Sends the whole body as a single chunk
+fn encode_chunked(body: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(body.len() + 32);
+    if !body.is_empty() {
+        encoded.extend_from_slice(format!("{:X}\r\n", body.len()).as_bytes());
+        encoded.extend_from_slice(body);
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded.extend_from_slice(b"0\r\n\r\n");
+    encoded
 }
 
-fn convert_http_request<T>(request: ParseRequest, body: T) -> Request<T> {
+fn convert_http_request<T>(request: ParseRequest, body: T) -> Result<Request<T>, http::Error> {
+    // httparse only accepts HTTP/1.0 and HTTP/1.1
+    let version = match request.version {
+        Some(0) => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    };
     let builder = Request::builder()
         .method(request.method.unwrap())
         .uri(request.path.unwrap())
-        .version(Version::HTTP_11);
+        .version(version);
     let builder = request.headers.iter().fold(builder, |builder, header| {
         builder.header(header.name, header.value)
     });
-    builder.body(body).unwrap()
+    builder.body(body)
 }
 
-pub struct HttpServer {
-    socket: TcpListener,
+/// The address of the client that sent a request, available in the
+/// request's extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerAddr(pub SocketAddr);
+
+pub struct HttpServer<L = TcpListener> {
+    socket: L,
+    config: Config,
+    metrics: Arc<ServerMetrics>,
+    panic_hook: Option<PanicHook>,
 }
 
 impl HttpServer {
     pub fn bind(addr: &str) -> Result<HttpServer, Error> {
         let socket = TcpListener::bind(addr)?;
-        Ok(HttpServer { socket })
+        Ok(HttpServer::from_listener(socket))
+    }
+}
+
+impl HttpServer<UnixListener> {
+    /// Binds to a Unix domain socket at `path`. Use `UnixListener` and
+    /// `HttpServer::from_listener` to also set the socket's permissions.
+    pub fn bind_unix(path: impl AsRef<Path>) -> Result<HttpServer<UnixListener>, Error> {
+        let socket = UnixListener::bind(path)?;
+        Ok(HttpServer::from_listener(socket))
+    }
+}
+
+impl<L: Listener> HttpServer<L> {
+    pub fn from_listener(socket: L) -> HttpServer<L> {
+        HttpServer {
+            socket,
+            config: Config {
+                read_timeout: Some(DEFAULT_READ_TIMEOUT),
+                max_body_size: DEFAULT_MAX_BODY_SIZE,
+            },
+            metrics: Arc::new(ServerMetrics::default()),
+            panic_hook: None,
+        }
+    }
+
+    /// Sets how long a client has to send a complete request (30 seconds by
+    /// default) before the connection is closed.
+    pub fn read_timeout(mut self, timeout: Duration) -> HttpServer<L> {
+        self.config.read_timeout = Some(timeout);
+        self
     }
 
-    pub async fn serve<H, R>(self, handler: H)
+    /// Sets the largest request body that will be accepted (1 MiB by
+    /// default). Larger requests get a 413 response.
+    pub fn max_body_size(mut self, max: usize) -> HttpServer<L> {
+        self.config.max_body_size = max;
+        self
+    }
+
+    /// Sets the panic hook (see `Runtime::set_panic_hook`) of each of the
+    /// runtimes started by `run_on_threads`. With `serve`, panics go to the
+    /// hook of the runtime it's running on instead.
+    pub fn panic_hook<F>(mut self, hook: F) -> HttpServer<L>
     where
-        H: (Fn(Request<&[u8]>) -> R) + 'static + std::marker::Send + Sync,
-        R: Future<Output = Response<Vec<u8>>> + 'static + std::marker::Send,
+        F: Fn(&(dyn Any + std::marker::Send)) + std::marker::Send + Sync + 'static,
     {
+        self.panic_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Counters that are updated as the server handles connections.
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        self.metrics.clone()
+    }
+
+    pub async fn serve<H: Handler>(self, handler: H) {
         // Handler is wrapped in an Arc so it can be cloned each time a task is spawned
         let handler = Arc::new(handler);
 
         // Accept loop
         loop {
-            // TODO have more than 1 accept call in flight
-            let fd = Accept::submit(&self.socket).await.unwrap();
-            let stream = unsafe { TcpStream::from_raw_fd(fd as i32) };
+            let stream = self.accept().await;
 
             let handler_clone = handler.clone();
 
-            spawn(HttpServer::handle_http_requests(stream, handler_clone));
+            spawn_local(Self::handle_connection(
+                stream,
+                handler_clone,
+                self.config,
+                self.metrics.clone(),
+            ));
         }
     }
 
-    pub fn run_on_threads<H, R>(self, threads: usize, handler: H)
-    where
-        H: (Fn(Request<&[u8]>) -> R) + 'static + std::marker::Send + Sync,
-        R: Future<Output = Response<Vec<u8>>> + 'static + std::marker::Send,
-    {
+    pub fn run_on_threads<H: Handler>(self, threads: usize, handler: H) {
         // Handler is wrapped in an Arc so it can be cloned each time a task is spawned
         let handler = Arc::new(handler);
 
@@ -91,12 +477,16 @@ impl HttpServer {
         // Spawn worker threads
         for worker in 0..num_workers {
             let handler = handler.clone();
-            let (sender, mut receiver) = unbounded::<TcpStream>();
+            let config = self.config;
+            let metrics = self.metrics.clone();
+            let panic_hook = self.panic_hook.clone();
+            let (sender, mut receiver) = unbounded::<L::Connection>();
             channels.push(sender);
 
             // TODO should we do something with the JoinHandle returned by this spawn call?
             spawn_thread(move || {
                 let mut runtime = Runtime::new();
+                set_panic_hook(&mut runtime, panic_hook);
                 let span = span!(Level::TRACE, "worker_thread", worker = worker);
                 let _enter = span.enter();
                 runtime.block_on(async move {
@@ -104,16 +494,23 @@ impl HttpServer {
                     // from the main thread is closed
                     while let Some(stream) = receiver.next().await {
                         trace!("worker got stream");
-                        spawn(HttpServer::handle_http_requests(stream, handler.clone()));
+                        spawn_local(Self::handle_connection(
+                            stream,
+                            handler.clone(),
+                            config,
+                            metrics.clone(),
+                        ));
                     }
                 });
-                let _runtime = runtime;
+                // Let the connections that are still open finish
+                runtime.run();
                 debug!("thread exiting");
             });
         }
 
         // Set up accept loop
         let mut runtime = Runtime::new();
+        set_panic_hook(&mut runtime, self.panic_hook.clone());
 
         runtime.block_on(async move {
             let span = span!(Level::TRACE, "accept_thread");
@@ -122,106 +519,813 @@ impl HttpServer {
 
             // Accept loop
             loop {
-                // TODO have more than 1 accept call in flight
-                let fd = Accept::submit(&self.socket).await.unwrap();
-                let stream = unsafe { TcpStream::from_raw_fd(fd as i32) };
+                let stream = self.accept().await;
 
                 // Send streams to workers in round-robin fashion
                 // TODO determine which workers are busy before sending
                 channels[next_worker]
                     .send(stream)
                     .await
-                    .expect(&format!("error sending stream to worker {}", next_worker));
+                    .unwrap_or_else(|_| panic!("error sending stream to worker {}", next_worker));
                 next_worker = (next_worker + 1) % num_workers;
             }
         });
     }
 
-    async fn handle_http_requests<H, R>(mut stream: TcpStream, handler: Arc<H>)
-    where
-        H: (Fn(Request<&[u8]>) -> R) + 'static + std::marker::Send + Sync,
-        R: Future<Output = Response<Vec<u8>>> + 'static + std::marker::Send,
-    {
-        let mut buf: Vec<u8> = Vec::with_capacity(512);
-        let mut curr_chunk = 0;
-
+    // Accepts the next connection. Errors that only affect the connection
+    // being accepted are skipped, but other errors would most likely happen
+    // again straight away, so the next attempt waits a while
+    async fn accept(&self) -> L::Connection {
         loop {
-            // Make sure the buf has enough space for the next chunk to be read
-            buf.extend_from_slice(&[0; BUF_SIZE]);
-
-            // Receive the next chunk
-            let start = curr_chunk * BUF_SIZE;
-            let end = start + BUF_SIZE;
-            // TODO read more than 512 bytes at a time
-            let bytes_received = Recv::submit(&mut buf[start..end], &mut stream)
-                .await
-                .unwrap();
-            curr_chunk += 1;
-
-            if bytes_received == 0 {
-                Close::submit(stream).await.unwrap();
-                break;
+            // TODO have more than 1 accept call in flight
+            let err = match Accept::submit(&self.socket).await {
+                Ok(fd) => return unsafe { L::Connection::from_raw_fd(fd as i32) },
+                Err(err) => err,
+            };
+            self.metrics.accept_errors.fetch_add(1, Ordering::Relaxed);
+            if is_connection_error(&err) {
+                debug!("Error accepting connection: {}", err);
+            } else {
+                error!(
+                    "Error accepting connection, retrying in {:?}: {}",
+                    ACCEPT_BACKOFF, err
+                );
+                sleep(ACCEPT_BACKOFF).await;
             }
+        }
+    }
 
-            // TODO what if there are more than this number of headers?
-            let mut headers = [EMPTY_HEADER; 24];
-            let mut request = ParseRequest::new(&mut headers);
-
-            // If we've gotten a complete request, call the handler with it
-            // and return the response. If not, call recv again
-            match request.parse(&buf) {
-                Ok(Status::Complete(body_start)) => {
-                    // Find and parse the Content-Length header
-                    let content_length: Option<usize> = request
-                        .headers
-                        .iter()
-                        .find(|header| header.name.eq_ignore_ascii_case("content-length"))
-                        .and_then(|header| str::from_utf8(header.value).ok())
-                        .and_then(|s| s.parse::<usize>().ok());
-
-                    // Use the Content-Length header to determine whether we've received the full request body
-                    let (request, body) = match content_length {
-                        Some(content_length) if body_start + content_length <= buf.len() => {
-                            trace!("Got request with content-length: {}", content_length);
-                            (request, Some(&buf[body_start..body_start + content_length]))
-                        }
-                        Some(content_length) => {
-                            // We haven't read the whole body yet
-                            trace!("Request has content-length ({}) but we have only read {} bytes from the body so far", content_length, buf.len() - body_start);
-                            continue;
-                        }
-                        None => {
-                            // TODO should we error or figure out the length of the body
-                            // some other way if no Content-Length header is found
-                            trace!(
-                                "Got request with no content-length header. Ignoring request body"
-                            );
-                            (request, None)
-                        }
-                    };
+    async fn handle_connection<H: Handler>(
+        mut stream: L::Connection,
+        handler: Arc<H>,
+        config: Config,
+        metrics: Arc<ServerMetrics>,
+    ) {
+        metrics.connections.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = Self::handle_http_requests(&mut stream, &*handler, config).await {
+            error!("Connection error: {}", err);
+            metrics.record(&err);
+        }
+
+        // The socket is closed however the connection ended
+        if let Err(err) = Close::submit(stream).await {
+            error!("Error closing connection: {}", err);
+        }
+    }
+
+    async fn handle_http_requests<H: Handler>(
+        stream: &mut L::Connection,
+        handler: &H,
+        config: Config,
+    ) -> Result<(), ServerError> {
+        let fd = stream.as_raw_fd();
+        let mut buf: Vec<u8> = Vec::with_capacity(BUF_SIZE);
+        let mut served_requests = 0;
+        let mut connection_handler = handler.connection_handler();
 
-                    let request = convert_http_request(request, body.unwrap_or(&[]));
-                    let response = (handler)(request).await;
-                    let mut response_buf = serialize_response(response);
-                    Send::submit(&mut response_buf, &mut stream).await.unwrap();
-                    break;
+        // Requests are handled one at a time until the client (or handler)
+        // asks to close the connection
+        loop {
+            let start = Instant::now();
+            let read = read_request(stream, &mut buf, config.max_body_size, handler);
+            let (request, body) = match read_within(fd, config.read_timeout, start, read).await {
+                Ok(ReadRequest::Head(request, body)) => (request, body),
+                Ok(ReadRequest::Closed) => return Ok(()),
+                // The body of a rejected request was never read, so the
+                // connection can't be used for another request
+                Ok(ReadRequest::Rejected(mut response)) => {
+                    trace!("rejected request before reading its body");
+                    response
+                        .headers_mut()
+                        .insert(CONNECTION, HeaderValue::from_static("close"));
+                    send_all(stream, &serialize_response(response, false)).await?;
+                    return Ok(());
                 }
-                Ok(Status::Partial) => {
-                    trace!("Got partial HTTP request");
-                    continue;
+                // An idle connection timing out between requests is not an error
+                Err(ServerError::Timeout) if served_requests > 0 && buf.is_empty() => {
+                    trace!("closing idle connection");
+                    return Ok(());
                 }
                 Err(err) => {
-                    error!("Invalid HTTP request: {:?}", err);
-                    let response = Response::builder()
-                        .status(400)
-                        .body("Invalid HTTP Request".as_bytes().to_vec())
-                        .unwrap();
-                    Send::submit(&mut serialize_response(response), &mut stream)
-                        .await
-                        .unwrap();
-                    break;
+                    // Tell the client why the connection is being closed. This is
+                    // best effort, because the connection is closed either way
+                    if let Some(mut response) = err.response() {
+                        response
+                            .headers_mut()
+                            .insert(CONNECTION, HeaderValue::from_static("close"));
+                        let _ = send_all(stream, &serialize_response(response, false)).await;
+                    }
+                    return Err(err);
                 }
+            };
+            served_requests += 1;
+
+            let keep_alive = if let Some(call) = handler.streaming(&request) {
+                respond_streaming(stream, &mut buf, call, request, body, config).await?
+            } else {
+                // The body has to arrive within the same timeout as the head
+                let read = read_body(stream, &mut buf, body.end);
+                read_within(fd, config.read_timeout, start, read).await?;
+                let call = |request: Request<&[u8]>| match &mut connection_handler {
+                    Some(connection_handler) => Either::Left(connection_handler(request)),
+                    None => Either::Right(handler.call(request)),
+                };
+                respond_buffered(stream, &mut buf, call, request, body).await?
+            };
+            if !keep_alive {
+                return Ok(());
             }
         }
     }
 }
+
+// Passes a request whose whole body is in `buf` to the handler and sends the
+// response, returning whether the connection can be used for another request
+async fn respond_buffered<C, F, R>(
+    stream: &mut C,
+    buf: &mut Vec<u8>,
+    call: F,
+    request: Request<()>,
+    body: Range<usize>,
+) -> Result<bool, ServerError>
+where
+    C: Connection,
+    F: FnOnce(Request<&[u8]>) -> R,
+    R: Future<Output = Response<Vec<u8>>>,
+{
+    let version = request.version();
+    let head_request = request.method() == Method::HEAD;
+    let keep_alive = wants_keep_alive(version, request.headers());
+    let request_len = body.end;
+    let mut request = request.map(|_| &buf[body]);
+    if let Some(addr) = stream.peer_addr() {
+        request.extensions_mut().insert(PeerAddr(addr));
+    }
+    // A panicking handler only fails its own request, rather
+    // than the connection task (and the other requests on it)
+    let mut response = match AssertUnwindSafe(async { call(request).await })
+        .catch_unwind()
+        .await
+    {
+        Ok(response) => response,
+        Err(payload) => {
+            report_panic(&*payload);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    };
+
+    let keep_alive = connection_headers(&mut response, version, keep_alive);
+    let response_buf = serialize_response(response, head_request);
+    send_all(stream, &response_buf).await?;
+
+    // Keep any bytes of the next request that were already received
+    buf.drain(..request_len);
+    Ok(keep_alive)
+}
+
+// Passes a request to the handler without waiting for its body, and sends
+// the response body as the handler produces it, returning whether the
+// connection can be used for another request
+async fn respond_streaming<C: Connection>(
+    stream: &mut C,
+    buf: &mut Vec<u8>,
+    call: StreamingCall,
+    request: Request<()>,
+    body: Range<usize>,
+    config: Config,
+) -> Result<bool, ServerError> {
+    let version = request.version();
+    let head_request = request.method() == Method::HEAD;
+    let keep_alive = wants_keep_alive(version, request.headers());
+
+    // The part of the body that was read along with the head is handed
+    // over, and the handler reads the rest straight from the connection
+    let buffered = buf.len().min(body.end);
+    let source = BodySource::new(stream.as_raw_fd(), config.read_timeout, body.end - buffered);
+    let request_body = RequestBody::new(buf[body.start..buffered].to_vec(), source.clone());
+    buf.drain(..buffered);
+    let mut request = request.map(|()| request_body);
+    if let Some(addr) = stream.peer_addr() {
+        request.extensions_mut().insert(PeerAddr(addr));
+    }
+    let mut response = match AssertUnwindSafe(async { call(request).await })
+        .catch_unwind()
+        .await
+    {
+        Ok(response) => response,
+        Err(payload) => {
+            report_panic(&*payload);
+            let response = (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error");
+            response.into_response().map(body::full)
+        }
+    };
+
+    // Any of the request body that the handler didn't read would be taken
+    // for the start of the next request
+    let framing = stream_framing(&mut response, version, head_request);
+    let keep_alive =
+        keep_alive && source.remaining() == 0 && !matches!(framing, StreamFraming::Close);
+    let keep_alive = connection_headers(&mut response, version, keep_alive);
+    let (mut parts, response_body) = response.into_parts();
+    let result = async {
+        send_all(stream, &serialize_head(&mut parts)).await?;
+        send_body_stream(stream, response_body, framing).await
+    }
+    .await;
+
+    // The handler may still have been reading the body while the response
+    // was sent, but it can't once the connection moves on
+    let body_read = source.close();
+    result?;
+    Ok(keep_alive && body_read)
+}
+
+// Sets the response's version and the Connection header that tells the
+// client whether the connection stays open, returning whether it does
+fn connection_headers<T>(response: &mut Response<T>, version: Version, keep_alive: bool) -> bool {
+    // The handler can also ask for the connection to be closed
+    let keep_alive = keep_alive && !has_connection_option(response.headers(), "close");
+    *response.version_mut() = version;
+    if version == Version::HTTP_10 {
+        // HTTP/1.0 clients don't understand chunked bodies, and need
+        // to be told when the connection is persistent
+        response.headers_mut().remove(TRANSFER_ENCODING);
+        if keep_alive {
+            response
+                .headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+        }
+    } else if !keep_alive {
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    keep_alive
+}
+
+// Errors from accept that belong to the connection being accepted (see
+// accept(2)), rather than to the listener or the process, like EMFILE
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(
+            libc::ECONNABORTED
+                | libc::EINTR
+                | libc::EAGAIN
+                | libc::EPROTO
+                | libc::EPERM
+                | libc::ENETDOWN
+                | libc::ENETUNREACH
+                | libc::ENOPROTOOPT
+                | libc::EHOSTDOWN
+                | libc::EHOSTUNREACH
+                | libc::ENONET
+                | libc::EOPNOTSUPP
+        )
+    )
+}
+
+fn set_panic_hook(runtime: &mut Runtime, hook: Option<PanicHook>) {
+    if let Some(hook) = hook {
+        runtime.set_panic_hook(move |payload| hook(payload));
+    }
+}
+
+// HTTP/1.1 connections are persistent unless either side asks to close them,
+// while HTTP/1.0 connections are closed unless the client asks to keep them
+fn wants_keep_alive(version: Version, headers: &HeaderMap) -> bool {
+    if version == Version::HTTP_10 {
+        has_connection_option(headers, "keep-alive")
+    } else {
+        !has_connection_option(headers, "close")
+    }
+}
+
+fn has_connection_option(headers: &HeaderMap, option: &str) -> bool {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(option))
+}
+
+// What `read_request` read from the connection
+enum ReadRequest {
+    // The connection was closed before any of a request arrived
+    Closed,
+    // The request's head, and the range of the read buffer that holds its
+    // body, which may not have all been read yet
+    Head(Request<()>, Range<usize>),
+    // The request was answered before its body was read
+    Rejected(Response<Vec<u8>>),
+}
+
+// How a request with a body that hasn't arrived yet wants it to be sent
+enum Expectation {
+    None,
+    Continue,
+    Unsupported,
+}
+
+// Reads the head of a request into `buf`, which may already hold some or all
+// of the request
+async fn read_request<C: AsRawFd, H: Handler>(
+    stream: &mut C,
+    buf: &mut Vec<u8>,
+    max_body_size: usize,
+    handler: &H,
+) -> Result<ReadRequest, ServerError> {
+    loop {
+        if !buf.is_empty() {
+            if let Some((request, body)) = parse_request(buf, max_body_size)? {
+                // Clients that send `Expect: 100-continue` wait to be told to
+                // send the body, which gives the handler a chance to reject
+                // the request first
+                if buf.len() < body.end {
+                    match expectation(&request) {
+                        Expectation::None => {}
+                        Expectation::Continue => match handler.check_continue(&request) {
+                            Some(response) => return Ok(ReadRequest::Rejected(response)),
+                            None => send_all(stream, b"HTTP/1.1 100 Continue\r\n\r\n").await?,
+                        },
+                        Expectation::Unsupported => {
+                            let status = StatusCode::EXPECTATION_FAILED;
+                            let response = (status, status.canonical_reason().unwrap_or(""));
+                            return Ok(ReadRequest::Rejected(response.into_response()));
+                        }
+                    }
+                }
+                return Ok(ReadRequest::Head(request, body));
+            }
+        }
+
+        if fill(stream, buf).await? == 0 {
+            if buf.is_empty() {
+                return Ok(ReadRequest::Closed);
+            }
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before the request was complete",
+            )
+            .into());
+        }
+    }
+}
+
+// Reads until `buf` holds the whole body of the request, which ends at `end`
+async fn read_body<C: AsRawFd>(
+    stream: &mut C,
+    buf: &mut Vec<u8>,
+    end: usize,
+) -> Result<(), ServerError> {
+    while buf.len() < end {
+        if fill(stream, buf).await? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before the request was complete",
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// Runs a read of a request that has to be complete within the read timeout,
+// which started at `start`
+async fn read_within<F, T>(
+    fd: RawFd,
+    read_timeout: Option<Duration>,
+    start: Instant,
+    read: F,
+) -> Result<T, ServerError>
+where
+    F: Future<Output = Result<T, ServerError>>,
+{
+    match read_timeout {
+        Some(timeout) => with_deadline(fd, timeout.saturating_sub(start.elapsed()), read)
+            .await
+            .unwrap_or(Err(ServerError::Timeout)),
+        None => read.await,
+    }
+}
+
+// HTTP/1.0 clients don't know about expectations, so they are ignored
+// (RFC 9110 section 10.1.1)
+fn expectation(request: &Request<()>) -> Expectation {
+    match request.headers().get(EXPECT) {
+        Some(_) if request.version() == Version::HTTP_10 => Expectation::None,
+        Some(value) if value.as_bytes().eq_ignore_ascii_case(b"100-continue") => {
+            Expectation::Continue
+        }
+        Some(_) => Expectation::Unsupported,
+        None => Expectation::None,
+    }
+}
+
+// Parses the head of the request at the start of `buf`, returning None if
+// more of it needs to be read. The range of the body may extend past the end
+// of `buf` if the body hasn't all been read yet.
+fn parse_request(buf: &[u8], max_body_size: usize) -> Result<Option<ParsedRequest>, ServerError> {
+    let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+    let mut request = ParseRequest::new(&mut headers);
+
+    let body_start = match request.parse(buf) {
+        Ok(Status::Complete(body_start)) => body_start,
+        Ok(Status::Partial) if buf.len() > MAX_HEAD_SIZE => {
+            return Err(ServerError::Limit(Limit::HeaderSize));
+        }
+        Ok(Status::Partial) => {
+            trace!("Got partial HTTP request");
+            return Ok(None);
+        }
+        Err(httparse::Error::TooManyHeaders) => {
+            return Err(ServerError::Limit(Limit::HeaderCount));
+        }
+        Err(httparse::Error::Version) => return Err(ServerError::UnsupportedVersion),
+        Err(err) => return Err(err.into()),
+    };
+
+    // HTTP/1.1 requests must say which host they are for (RFC 9112 section 3.2)
+    let hosts = request
+        .headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("host"))
+        .count();
+    if request.version == Some(1) && hosts != 1 {
+        return Err(ServerError::BadRequest(
+            "HTTP/1.1 requests must have exactly one Host header",
+        ));
+    }
+
+    // The body's length has to be unambiguous, or a proxy in front of the
+    // server could disagree about where the request ends and smuggle another
+    // one in after it (RFC 9112 section 6.3). Chunked bodies aren't
+    // supported, so any Transfer-Encoding is rejected.
+    let mut content_lengths = request
+        .headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("content-length"));
+    let content_length = content_lengths.next();
+    let has_transfer_encoding = request
+        .headers
+        .iter()
+        .any(|header| header.name.eq_ignore_ascii_case("transfer-encoding"));
+    if has_transfer_encoding && content_length.is_some() {
+        return Err(ServerError::BadRequest(
+            "requests can't have both Content-Length and Transfer-Encoding",
+        ));
+    }
+    if has_transfer_encoding {
+        return Err(ServerError::UnsupportedTransferEncoding);
+    }
+    if content_lengths.next().is_some() {
+        return Err(ServerError::BadRequest(
+            "requests can't have more than one Content-Length",
+        ));
+    }
+    let content_length = match content_length {
+        Some(header) => parse_content_length(header.value).ok_or(ServerError::BadRequest(
+            "Content-Length must be a decimal number",
+        ))?,
+        None => {
+            trace!("Got request with no content-length header, so it has no body");
+            0
+        }
+    };
+    if content_length > max_body_size {
+        return Err(ServerError::Limit(Limit::BodySize));
+    }
+
+    // Use the Content-Length header to determine whether we've received the full request body
+    if buf.len() < body_start + content_length {
+        trace!(
+            "Request has content-length ({}) but we have only read {} bytes from the body so far",
+            content_length,
+            buf.len() - body_start
+        );
+    }
+
+    let request = convert_http_request(request, ())?;
+    Ok(Some((request, body_start..body_start + content_length)))
+}
+
+// Only digits are allowed, unlike `usize::from_str` which also accepts a sign
+fn parse_content_length(value: &[u8]) -> Option<usize> {
+    let value = str::from_utf8(value).ok()?.trim();
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+// Reads more data from the stream onto the end of `buf`, returning the number
+// of bytes read
+async fn fill<C: AsRawFd>(stream: &mut C, buf: &mut Vec<u8>) -> Result<usize, io::Error> {
+    let start = buf.len();
+    buf.resize(start + BUF_SIZE, 0);
+    let result = Recv::submit(&mut buf[start..], stream).await;
+    buf.truncate(start + *result.as_ref().unwrap_or(&0) as usize);
+    result.map(|received| received as usize)
+}
+
+async fn send_all<C: AsRawFd>(stream: &mut C, buf: &[u8]) -> Result<(), io::Error> {
+    let mut sent = 0;
+    while sent < buf.len() {
+        match Send::submit(&buf[sent..], stream).await? {
+            0 => return Err(ErrorKind::WriteZero.into()),
+            n => sent += n as usize,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Option<ParsedRequest>, ServerError> {
+        parse_request(raw.as_bytes(), DEFAULT_MAX_BODY_SIZE)
+    }
+
+    #[test]
+    fn body_is_framed_by_content_length() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nabcdGET";
+        let (request, body) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(&raw[body], "abcd");
+    }
+
+    #[test]
+    fn transfer_encoding_is_not_implemented() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(
+            parse(raw),
+            Err(ServerError::UnsupportedTransferEncoding)
+        ));
+    }
+
+    #[test]
+    fn content_length_with_transfer_encoding_is_rejected() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\
+                   Transfer-Encoding: chunked\r\n\r\n20\r\n";
+        assert!(matches!(parse(raw), Err(ServerError::BadRequest(_))));
+    }
+
+    #[test]
+    fn duplicate_content_length_is_rejected() {
+        for raw in [
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nContent-Length: 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nContent-Length: 4\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4, 4\r\n\r\n",
+        ] {
+            assert!(
+                matches!(parse(raw), Err(ServerError::BadRequest(_))),
+                "{}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn content_length_must_be_digits() {
+        for value in ["+4", "-1", "0x4", "", "4 4"] {
+            let raw = format!(
+                "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n",
+                value
+            );
+            assert!(
+                matches!(parse(&raw), Err(ServerError::BadRequest(_))),
+                "{}",
+                value
+            );
+        }
+    }
+
+    fn framing(mut response: Response<()>, version: Version, head_request: bool) -> StreamFraming {
+        stream_framing(&mut response, version, head_request)
+    }
+
+    #[test]
+    fn streamed_body_is_chunked_without_content_length() {
+        let mut response = Response::new(());
+        assert_eq!(
+            stream_framing(&mut response, Version::HTTP_11, false),
+            StreamFraming::Chunked
+        );
+        assert_eq!(response.headers()[TRANSFER_ENCODING], "chunked");
+        assert_eq!(
+            framing(Response::new(()), Version::HTTP_10, false),
+            StreamFraming::Close
+        );
+    }
+
+    #[test]
+    fn streamed_body_uses_content_length() {
+        let response = Response::builder()
+            .header(CONTENT_LENGTH, "12")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            framing(response, Version::HTTP_10, false),
+            StreamFraming::Length(12)
+        );
+    }
+
+    #[test]
+    fn streamed_body_is_not_sent_for_head_requests() {
+        let response = Response::builder()
+            .header(CONTENT_LENGTH, "12")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            framing(response, Version::HTTP_11, true),
+            StreamFraming::None
+        );
+        let response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(())
+            .unwrap();
+        assert_eq!(
+            framing(response, Version::HTTP_11, false),
+            StreamFraming::None
+        );
+    }
+
+    #[test]
+    fn large_body_is_rejected() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\n";
+        assert!(matches!(
+            parse_request(raw.as_bytes(), 4),
+            Err(ServerError::Limit(Limit::BodySize))
+        ));
+    }
+
+    // A serialized response split back into its status line, headers and body
+    struct Serialized {
+        status_line: String,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    }
+
+    fn serialize(response: Response<Vec<u8>>, head_request: bool) -> Serialized {
+        let raw = serialize_response(response, head_request);
+        let end = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let mut lines = raw[..end].split(|&b| b == b'\n');
+        let status_line = String::from_utf8(lines.next().unwrap().to_vec()).unwrap();
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let colon = line.iter().position(|&b| b == b':').unwrap();
+            headers.append(
+                http::header::HeaderName::from_bytes(&line[..colon]).unwrap(),
+                HeaderValue::from_bytes(&line[colon + 2..]).unwrap(),
+            );
+        }
+        Serialized {
+            status_line: status_line.trim_end().to_string(),
+            headers,
+            body: raw[end + 4..].to_vec(),
+        }
+    }
+
+    fn response(status: StatusCode, headers: &[(&str, &str)], body: &str) -> Response<Vec<u8>> {
+        let builder = headers.iter().fold(
+            Response::builder().status(status),
+            |builder, (name, value)| builder.header(*name, *value),
+        );
+        builder.body(body.as_bytes().to_vec()).unwrap()
+    }
+
+    fn values(headers: &HeaderMap, name: http::header::HeaderName) -> Vec<&[u8]> {
+        headers.get_all(name).iter().map(|v| v.as_bytes()).collect()
+    }
+
+    #[test]
+    fn content_length_is_not_masked_by_later_headers() {
+        let serialized = serialize(
+            response(
+                StatusCode::OK,
+                &[("x-first", "content-length"), ("x-last", "1")],
+                "hello",
+            ),
+            false,
+        );
+        assert_eq!(values(&serialized.headers, CONTENT_LENGTH), [b"5"]);
+        assert_eq!(serialized.body, b"hello");
+    }
+
+    #[test]
+    fn handler_content_length_is_replaced_by_body_length() {
+        let serialized = serialize(
+            response(StatusCode::OK, &[("content-length", "100")], "hello"),
+            false,
+        );
+        assert_eq!(values(&serialized.headers, CONTENT_LENGTH), [b"5"]);
+        assert_eq!(serialized.body, b"hello");
+    }
+
+    #[test]
+    fn transfer_encoded_body_is_chunked() {
+        let serialized = serialize(
+            response(
+                StatusCode::OK,
+                &[("transfer-encoding", "gzip"), ("content-length", "5")],
+                "hello",
+            ),
+            false,
+        );
+        assert!(!serialized.headers.contains_key(CONTENT_LENGTH));
+        assert_eq!(
+            values(&serialized.headers, TRANSFER_ENCODING),
+            [b"gzip, chunked"]
+        );
+        assert_eq!(serialized.body, b"5\r\nhello\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn head_response_keeps_framing_without_body() {
+        let serialized = serialize(response(StatusCode::OK, &[], "hello"), true);
+        assert_eq!(values(&serialized.headers, CONTENT_LENGTH), [b"5"]);
+        assert!(serialized.body.is_empty());
+
+        let serialized = serialize(
+            response(StatusCode::OK, &[("transfer-encoding", "chunked")], "hello"),
+            true,
+        );
+        assert_eq!(values(&serialized.headers, TRANSFER_ENCODING), [b"chunked"]);
+        assert!(!serialized.headers.contains_key(CONTENT_LENGTH));
+        assert!(serialized.body.is_empty());
+    }
+
+    #[test]
+    fn not_modified_keeps_framing_without_body() {
+        let serialized = serialize(
+            response(
+                StatusCode::NOT_MODIFIED,
+                &[("content-length", "100")],
+                "hello",
+            ),
+            false,
+        );
+        assert_eq!(values(&serialized.headers, CONTENT_LENGTH), [b"100"]);
+        assert!(serialized.body.is_empty());
+
+        let serialized = serialize(response(StatusCode::NOT_MODIFIED, &[], "hello"), false);
+        assert!(!serialized.headers.contains_key(CONTENT_LENGTH));
+        assert!(serialized.body.is_empty());
+    }
+
+    #[test]
+    fn bodiless_statuses_drop_body_and_framing() {
+        for status in [
+            StatusCode::CONTINUE,
+            StatusCode::SWITCHING_PROTOCOLS,
+            StatusCode::NO_CONTENT,
+        ] {
+            for headers in [
+                &[("content-length", "5")][..],
+                &[("transfer-encoding", "chunked")][..],
+            ] {
+                let serialized = serialize(response(status, headers, "hello"), false);
+                assert!(!serialized.headers.contains_key(CONTENT_LENGTH));
+                assert!(!serialized.headers.contains_key(TRANSFER_ENCODING));
+                assert!(serialized.body.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn non_utf8_header_values_are_written_unchanged() {
+        let response = Response::builder()
+            .header("x-name", HeaderValue::from_bytes(b"caf\xe9").unwrap())
+            .body(Vec::new())
+            .unwrap();
+        let raw = serialize_response(response, false);
+        assert!(raw
+            .windows(14)
+            .any(|window| window == b"x-name: caf\xe9\r\n"));
+    }
+
+    #[test]
+    fn status_line_and_default_headers_are_written() {
+        let serialized = serialize(response(StatusCode::NOT_FOUND, &[], ""), false);
+        assert_eq!(serialized.status_line, "HTTP/1.1 404 Not Found");
+        let date = serialized.headers[DATE].to_str().unwrap();
+        assert!(httpdate::parse_http_date(date).is_ok());
+        assert_eq!(
+            values(&serialized.headers, SERVER),
+            [SERVER_NAME.as_bytes()]
+        );
+
+        let serialized = serialize(response(StatusCode::OK, &[("server", "other")], ""), false);
+        assert_eq!(values(&serialized.headers, SERVER), [b"other"]);
+    }
+
+    #[test]
+    fn status_line_uses_response_version() {
+        let mut response = response(StatusCode::OK, &[], "");
+        *response.version_mut() = Version::HTTP_10;
+        assert_eq!(serialize(response, false).status_line, "HTTP/1.0 200 OK");
+    }
+}
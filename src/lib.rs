@@ -0,0 +1,18 @@
+pub mod body;
+mod executor;
+#[cfg(feature = "serde")]
+pub mod extract;
+pub mod handler;
+pub mod http_client;
+pub mod http_server;
+pub mod net;
+pub mod proxy;
+mod reactor;
+pub mod response;
+pub mod router;
+pub mod runtime;
+pub mod syscall;
+pub mod task;
+pub mod time;
+#[cfg(feature = "tower")]
+pub mod tower;
@@ -1,23 +1,12 @@
-mod executor;
-mod http_server;
-mod reactor;
-mod runtime;
-mod syscall;
-
-use http::{Request, Response, StatusCode};
-use http_server::HttpServer;
+use http::Request;
+use iou_http::http_server::HttpServer;
 #[allow(unused_imports)]
-use runtime::Runtime;
+use iou_http::runtime::Runtime;
 
 fn main() {
     tracing_subscriber::fmt::init();
 
-    let handler = |_request: Request<&[u8]>| async {
-        Response::builder()
-            .status(StatusCode::OK)
-            .body("hello world".as_bytes().to_vec())
-            .unwrap()
-    };
+    let handler = |_request: Request<&[u8]>| async { "hello world" };
 
     let server = HttpServer::bind("0.0.0.0:3000").expect("bind");
 
@@ -0,0 +1,74 @@
+//! Sockets whose I/O is driven by the runtime's io-uring reactor.
+
+use crate::time::sleep;
+use futures::future::{select, Either, Future};
+use futures::pin_mut;
+use std::net::{self, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use tracing::debug;
+
+mod tcp;
+mod udp;
+mod unix;
+
+pub use tcp::TcpStream;
+pub use udp::UdpSocket;
+pub use unix::UnixListener;
+
+/// A listening socket that the `HttpServer` accepts connections on.
+pub trait Listener: AsRawFd + Send + Sync + 'static {
+    type Connection: Connection;
+}
+
+/// A connection accepted from a `Listener`.
+pub trait Connection: AsRawFd + FromRawFd + IntoRawFd + Send + 'static {
+    /// The address of the remote peer, if the connection is over IP.
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Listener for net::TcpListener {
+    type Connection = net::TcpStream;
+}
+
+impl Connection for net::TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        net::TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl Listener for UnixListener {
+    type Connection = UnixStream;
+}
+
+impl Connection for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+// Races an operation on the socket `fd` against a timer, returning None if
+// the timer fires first. The operation borrows buffers that the kernel may
+// still be writing to, so it can't just be dropped when the timer fires.
+// Instead the socket is shut down, which makes any in-flight operations
+// complete right away, and the operation is driven to completion before
+// returning.
+pub(crate) async fn with_deadline<F: Future>(
+    fd: RawFd,
+    timeout: Duration,
+    operation: F,
+) -> Option<F::Output> {
+    pin_mut!(operation);
+    match select(operation, sleep(timeout)).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right((_, operation)) => {
+            debug!("operation timed out, shutting down socket");
+            unsafe {
+                libc::shutdown(fd, libc::SHUT_RDWR);
+            }
+            let _ = operation.await;
+            None
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::syscall::{Close, Connect, Recv, Send, Shutdown};
+use std::io::{Error, ErrorKind};
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+/// A TCP connection that reads and writes through the runtime.
+///
+/// Like the syscalls it is built on, the futures returned by `read` and
+/// `write` borrow the buffer until the kernel completes the operation, so
+/// they should be driven to completion rather than dropped part way through.
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    /// Opens a connection to `addr`, trying each resolved address in turn.
+    ///
+    /// Name resolution uses `ToSocketAddrs`, which blocks the thread, so
+    /// prefer passing an already resolved `SocketAddr`.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<TcpStream, Error> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_addr(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+
+    async fn connect_addr(addr: SocketAddr) -> Result<TcpStream, Error> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        // Wrap the socket right away so it is closed if the connect fails
+        let inner = unsafe { net::TcpStream::from_raw_fd(fd) };
+        Connect::submit(&inner, addr).await?;
+        Ok(TcpStream { inner })
+    }
+
+    /// Reads into `buf`, returning the number of bytes read. `Ok(0)` means
+    /// the peer has closed its side of the connection.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = Recv::submit(buf, &mut self.inner).await?;
+        Ok(n as usize)
+    }
+
+    /// Writes some of `buf`, returning the number of bytes written.
+    pub async fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let n = Send::submit(buf, &mut self.inner).await?;
+        Ok(n as usize)
+    }
+
+    /// Writes all of `buf`, submitting more sends if the kernel takes less
+    /// than the whole buffer at once.
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            match self.write(buf).await? {
+                0 => return Err(ErrorKind::WriteZero.into()),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Shuts down the read half, write half, or both halves of the connection.
+    pub async fn shutdown(&self, how: net::Shutdown) -> Result<(), Error> {
+        Shutdown::submit(&self.inner, how).await?;
+        Ok(())
+    }
+
+    /// Closes the socket through the runtime instead of with a blocking
+    /// `close` call when the stream is dropped.
+    pub async fn close(self) -> Result<(), Error> {
+        Close::submit(self.inner).await?;
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.inner.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.inner.local_addr()
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), Error> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    pub fn into_std(self) -> net::TcpStream {
+        self.inner
+    }
+}
+
+impl From<net::TcpStream> for TcpStream {
+    fn from(inner: net::TcpStream) -> TcpStream {
+        TcpStream { inner }
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for TcpStream {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
@@ -0,0 +1,108 @@
+use crate::syscall::sockaddr::SockAddr;
+use crate::syscall::{RecvMsg, SendMsg};
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::ptr;
+
+/// A UDP socket that sends and receives datagrams through the runtime.
+///
+/// Like `TcpStream`, the futures returned by `send_to` and `recv_from`
+/// borrow the buffer until the kernel completes the operation, so they should
+/// be driven to completion rather than dropped part way through.
+pub struct UdpSocket {
+    inner: net::UdpSocket,
+}
+
+// The message header and the iovec and address it points to. These are
+// only ever used from the future that owns them, while the buffer the iovec
+// points to is borrowed by that same future.
+struct Message {
+    header: libc::msghdr,
+    iov: libc::iovec,
+    addr: SockAddr,
+}
+
+unsafe impl std::marker::Send for Message {}
+
+impl Message {
+    fn new(buf: *mut u8, len: usize, addr: SockAddr) -> Message {
+        Message {
+            header: unsafe { mem::zeroed() },
+            iov: libc::iovec {
+                iov_base: buf as *mut libc::c_void,
+                iov_len: len,
+            },
+            addr,
+        }
+    }
+
+    // Points the header at the iovec and address. This must be called once
+    // the message is in its final location, since the header holds pointers
+    // into the message itself.
+    fn header(&mut self) -> &mut libc::msghdr {
+        self.header.msg_name = self.addr.as_mut_ptr() as *mut libc::c_void;
+        self.header.msg_namelen = self.addr.len;
+        self.header.msg_iov = &mut self.iov;
+        self.header.msg_iovlen = 1;
+        self.header.msg_control = ptr::null_mut();
+        self.header.msg_controllen = 0;
+        &mut self.header
+    }
+}
+
+impl UdpSocket {
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<UdpSocket, Error> {
+        let inner = net::UdpSocket::bind(addr)?;
+        Ok(UdpSocket { inner })
+    }
+
+    /// Sends a single datagram to `target`, returning the number of bytes sent.
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize, Error> {
+        // The kernel only reads from the buffer for a send
+        let mut message = Message::new(buf.as_ptr() as *mut u8, buf.len(), SockAddr::from(target));
+        let n = SendMsg::submit(message.header(), &self.inner).await?;
+        Ok(n as usize)
+    }
+
+    /// Receives a single datagram, returning its length and the address it
+    /// came from. If `buf` is too small the rest of the datagram is discarded.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        let mut message = Message::new(buf.as_mut_ptr(), buf.len(), SockAddr::empty());
+        let n = RecvMsg::submit(message.header(), &self.inner).await?;
+        let addr = message.addr.as_socket_addr().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "datagram has an unsupported address family",
+            )
+        })?;
+        Ok((n as usize, addr))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.inner.local_addr()
+    }
+
+    pub fn into_std(self) -> net::UdpSocket {
+        self.inner
+    }
+}
+
+impl From<net::UdpSocket> for UdpSocket {
+    fn from(inner: net::UdpSocket) -> UdpSocket {
+        UdpSocket { inner }
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for UdpSocket {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
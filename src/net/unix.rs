@@ -0,0 +1,83 @@
+use std::fs::{self, Permissions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{self, UnixStream};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// A Unix domain socket listener bound to a path on the filesystem.
+///
+/// The socket file is removed when the listener is dropped.
+pub struct UnixListener {
+    inner: net::UnixListener,
+    path: PathBuf,
+}
+
+impl UnixListener {
+    /// Binds to `path`, replacing a socket file left behind by a process
+    /// that is no longer listening on it.
+    pub fn bind(path: impl AsRef<Path>) -> Result<UnixListener, Error> {
+        let path = path.as_ref();
+        remove_stale_socket(path)?;
+        let inner = net::UnixListener::bind(path)?;
+        Ok(UnixListener {
+            inner,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Like `bind`, but also sets the permissions of the socket file (for
+    /// example `0o660` to only allow the owner and group to connect).
+    pub fn bind_with_permissions(path: impl AsRef<Path>, mode: u32) -> Result<UnixListener, Error> {
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(&listener.path, Permissions::from_mode(mode))?;
+        Ok(listener)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            debug!("unable to remove socket {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+// Removes the socket at `path` if nothing is listening on it anymore.
+// Anything other than a socket is left alone so we never delete a regular file.
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("another process is listening on {}", path.display()),
+        )),
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+            debug!("removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(err) => Err(err),
+    }
+}
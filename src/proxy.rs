@@ -0,0 +1,350 @@
+use crate::body::{self, BodyStream, RequestBody};
+use crate::handler::{Handler, StreamingCall};
+use crate::http_client::{ClientError, HttpClient};
+use crate::http_server::PeerAddr;
+use futures::future::{BoxFuture, Future, FutureExt};
+use http::header::{HeaderName, CONNECTION, HOST, UPGRADE};
+use http::uri::{PathAndQuery, Uri};
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+use std::net::SocketAddr;
+use std::time::Instant;
+use tracing::{debug, error};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// Headers that only apply to a single connection, which must not be
+// forwarded by proxies (RFC 9110 section 7.6.1)
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+];
+
+/// Reverse proxy that forwards requests to an upstream HTTP server.
+///
+/// The proxy is a `Handler`, so it can be used like this:
+///
+/// ```ignore
+/// let proxy = Proxy::new("http://localhost:8080")?;
+/// server.run_on_threads(8, proxy);
+/// ```
+///
+/// Request and response bodies are streamed through as they arrive, including
+/// when the proxy is mounted in a `Router` or wrapped in middleware.
+#[derive(Clone)]
+pub struct Proxy {
+    upstream: Uri,
+    client: HttpClient,
+}
+
+impl Proxy {
+    /// Creates a proxy to `upstream`, which must be an absolute `http` URI.
+    /// If it has a path, that path is prepended to the path of each request.
+    pub fn new(upstream: &str) -> Result<Proxy, ClientError> {
+        Proxy::with_client(upstream, HttpClient::new())
+    }
+
+    /// Like `new`, but uses the given client (for example to set timeouts).
+    pub fn with_client(upstream: &str, client: HttpClient) -> Result<Proxy, ClientError> {
+        let upstream: Uri = upstream.parse().map_err(http::Error::from)?;
+        match (upstream.scheme_str(), upstream.authority()) {
+            (Some("http"), Some(_)) => Ok(Proxy { upstream, client }),
+            _ => Err(ClientError::InvalidUri(upstream)),
+        }
+    }
+
+    /// Forwards a request whose whole body has already been read, and reads
+    /// the whole response.
+    pub fn handle(
+        &self,
+        request: Request<&[u8]>,
+    ) -> impl Future<Output = Response<Vec<u8>>> + 'static + Send {
+        let proxy = self.clone();
+        let request = proxy.upstream_request(request.map(<[u8]>::to_vec));
+        async move {
+            let request = match request {
+                Ok(request) => request,
+                Err(err) => {
+                    error!("Unable to build upstream request: {}", err);
+                    return error_response(StatusCode::BAD_GATEWAY);
+                }
+            };
+            debug!("proxying request to {}", request.uri());
+            downstream_response(proxy.client.send(request).await).unwrap_or_else(error_response)
+        }
+    }
+
+    /// Forwards a request, sending its body upstream as it arrives, and
+    /// returns the response as soon as its head has arrived.
+    pub fn handle_streaming(
+        &self,
+        request: Request<RequestBody>,
+    ) -> impl Future<Output = Response<BodyStream>> + 'static {
+        let proxy = self.clone();
+        // A client that stalls while sending the body would otherwise hold
+        // the exchange open past the upstream timeout, since that only
+        // interrupts operations on the upstream connection
+        let deadline = proxy
+            .client
+            .request_timeout()
+            .map(|timeout| Instant::now() + timeout);
+        let request = request.map(|mut body| {
+            if let Some(deadline) = deadline {
+                body.set_deadline(deadline);
+            }
+            body.into_stream()
+        });
+        let request = proxy.upstream_request(request);
+        async move {
+            let streamed_error = |status| error_response(status).map(body::full);
+            let request = match request {
+                Ok(request) => request,
+                Err(err) => {
+                    error!("Unable to build upstream request: {}", err);
+                    return streamed_error(StatusCode::BAD_GATEWAY);
+                }
+            };
+            debug!("proxying streamed request to {}", request.uri());
+            downstream_response(proxy.client.send_streaming(request).await)
+                .unwrap_or_else(streamed_error)
+        }
+    }
+
+    // Copies the incoming request into one addressed to the upstream server
+    fn upstream_request<B>(&self, request: Request<B>) -> Result<Request<B>, ClientError> {
+        let (mut parts, body) = request.into_parts();
+
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(PathAndQuery::as_str)
+            .unwrap_or("/");
+        let prefix = self.upstream.path().trim_end_matches('/');
+        let mut upstream_parts = self.upstream.clone().into_parts();
+        let path_and_query = format!("{}{}", prefix, path)
+            .parse()
+            .map_err(http::Error::from)?;
+        upstream_parts.path_and_query = Some(path_and_query);
+        parts.uri = Uri::from_parts(upstream_parts).map_err(http::Error::from)?;
+
+        // Upgrades are not supported, so don't ask the upstream for one
+        parts.headers.remove(UPGRADE);
+        remove_hop_by_hop_headers(&mut parts.headers);
+        if let Some(PeerAddr(addr)) = parts.extensions.get::<PeerAddr>() {
+            add_forwarded_headers(&mut parts.headers, *addr);
+        }
+
+        Ok(Request::from_parts(parts, body))
+    }
+}
+
+impl Handler for Proxy {
+    type Future = BoxFuture<'static, Response<Vec<u8>>>;
+
+    fn call(&self, request: Request<&[u8]>) -> Self::Future {
+        self.handle(request).boxed()
+    }
+
+    fn streaming(&self, _request: &Request<()>) -> Option<StreamingCall> {
+        let proxy = self.clone();
+        Some(Box::new(move |request| {
+            proxy.handle_streaming(request).boxed_local()
+        }))
+    }
+}
+
+// Turns the upstream's response into the one for the client, or picks the
+// status to respond with if there wasn't one
+fn downstream_response<B>(
+    result: Result<Response<B>, ClientError>,
+) -> Result<Response<B>, StatusCode> {
+    match result {
+        Ok(response) => {
+            let (mut parts, body) = response.into_parts();
+            remove_hop_by_hop_headers(&mut parts.headers);
+            Ok(Response::from_parts(parts, body))
+        }
+        Err(ClientError::Timeout) => {
+            error!("Upstream request timed out");
+            Err(StatusCode::GATEWAY_TIMEOUT)
+        }
+        Err(err) => {
+            error!("Upstream request failed: {}", err);
+            Err(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    // Connection can also list other headers that are specific to this connection
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS.iter() {
+        headers.remove(*name);
+    }
+}
+
+fn add_forwarded_headers(headers: &mut HeaderMap, peer: SocketAddr) {
+    let ip = peer.ip();
+
+    // Append the peer to the list left by any proxies in front of this one,
+    // which may be split over several headers
+    let mut forwarded_for = Vec::new();
+    for value in headers.get_all(X_FORWARDED_FOR) {
+        forwarded_for.extend_from_slice(value.as_bytes());
+        forwarded_for.extend_from_slice(b", ");
+    }
+    forwarded_for.extend_from_slice(ip.to_string().as_bytes());
+    if let Ok(value) = HeaderValue::from_bytes(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+
+    // IPv6 addresses have to be quoted and bracketed in Forwarded (RFC 7239)
+    let mut forwarded = match peer {
+        SocketAddr::V4(_) => format!("for={}", ip),
+        SocketAddr::V6(_) => format!("for=\"[{}]\"", ip),
+    };
+    if let Some(host) = headers.get(HOST).and_then(|value| value.to_str().ok()) {
+        forwarded.push_str(";host=");
+        forwarded.push_str(&quoted_string(host));
+    }
+    forwarded.push_str(";proto=http");
+    if let Ok(value) = HeaderValue::from_str(&forwarded) {
+        headers.append("forwarded", value);
+    }
+}
+
+// Quotes a value for Forwarded, escaping the characters that a quoted-string
+// can't contain as they are (RFC 9110 section 5.6.4)
+fn quoted_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn error_response(status: StatusCode) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .body(status.canonical_reason().unwrap_or("").as_bytes().to_vec())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::BodySource;
+    use crate::runtime::Runtime;
+    use crate::time::timeout;
+    use http::header::CONTENT_LENGTH;
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    fn peer() -> SocketAddr {
+        "10.0.0.1:5000".parse().unwrap()
+    }
+
+    #[test]
+    fn x_forwarded_for_keeps_every_earlier_value() {
+        let mut headers = HeaderMap::new();
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("1.1.1.1"));
+        headers.append(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("2.2.2.2, 3.3.3.3"),
+        );
+        add_forwarded_headers(&mut headers, peer());
+
+        let values: Vec<&HeaderValue> = headers.get_all(X_FORWARDED_FOR).iter().collect();
+        assert_eq!(values, ["1.1.1.1, 2.2.2.2, 3.3.3.3, 10.0.0.1"]);
+    }
+
+    #[test]
+    fn forwarded_quotes_the_host() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, HeaderValue::from_static(r#"a"b\c"#));
+        add_forwarded_headers(&mut headers, peer());
+        assert_eq!(
+            headers["forwarded"],
+            r#"for=10.0.0.1;host="a\"b\\c";proto=http"#
+        );
+    }
+
+    #[test]
+    fn forwarded_brackets_ipv6_peers() {
+        let mut headers = HeaderMap::new();
+        headers.append("forwarded", HeaderValue::from_static("for=1.1.1.1"));
+        add_forwarded_headers(&mut headers, "[::1]:5000".parse().unwrap());
+
+        let values: Vec<&HeaderValue> = headers.get_all("forwarded").iter().collect();
+        assert_eq!(values, ["for=1.1.1.1", r#"for="[::1]";proto=http"#]);
+        assert_eq!(headers[X_FORWARDED_FOR], "::1");
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_removed() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("close, x-private"));
+        headers.insert("x-private", HeaderValue::from_static("1"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+        headers.insert("x-public", HeaderValue::from_static("1"));
+        remove_hop_by_hop_headers(&mut headers);
+
+        let names: Vec<&str> = headers.keys().map(HeaderName::as_str).collect();
+        assert_eq!(names, ["x-public"]);
+    }
+
+    #[test]
+    fn gives_up_on_a_client_that_stalls_in_the_body() {
+        let mut runtime = Runtime::new();
+        // The upstream accepts the connection, but never reads or responds
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}", upstream.local_addr().unwrap());
+        let client = HttpClient::new().timeout(Duration::from_millis(200));
+        let proxy = Proxy::with_client(&uri, client).unwrap();
+
+        // The client sent the start of the body along with the head, and
+        // then nothing more
+        let (connection, _client) = UnixStream::pair().unwrap();
+        let source = BodySource::new(connection.as_raw_fd(), None, 100);
+        let body = RequestBody::new(b"hello".to_vec(), source);
+        let request = Request::post("/upload")
+            .header(CONTENT_LENGTH, 105)
+            .body(body)
+            .unwrap();
+
+        let start = Instant::now();
+        let response = runtime
+            .block_on(timeout(
+                Duration::from_secs(5),
+                proxy.handle_streaming(request),
+            ))
+            .expect("the proxy waited for the client past the upstream timeout");
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(matches!(
+            response.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT
+        ));
+    }
+}
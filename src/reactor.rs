@@ -1,113 +1,693 @@
-use io_uring::{
-    squeue::{Entry, PushError},
-    IoUring,
-};
+use io_uring::{opcode, squeue::Entry, types, IoUring};
+use slab::Slab;
+use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::convert::TryInto;
 use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
 use std::rc::Rc;
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use tracing::{debug, error, trace};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, trace, warn};
 
 use thiserror::Error;
 
-pub(crate) type ReactorSender = SyncSender<(Entry, Callback)>;
+use crate::time::wheel::TimerWheel;
+
+/// Handle used to register entries with a `Reactor` from the thread it runs on.
+#[derive(Clone)]
+pub(crate) struct ReactorHandle(Rc<RefCell<Inner>>);
+
+impl ReactorHandle {
+    /// Identifies the reactor, so an operation can't be mistaken for one
+    /// with the same user_data in another runtime.
+    pub fn id(&self) -> u64 {
+        self.0.borrow().id
+    }
+
+    // The entry is pushed straight onto the ring's submission queue, and is
+    // submitted to the kernel by the reactor's next tick (or sooner, if the
+    // queue fills up). `data` is kept alive until the operation completes.
+    // The user_data is returned so the caller can poll the operation and
+    // refer to it later (for example to cancel it).
+    pub fn register(&self, entry: Entry, data: Box<dyn Any>) -> u64 {
+        self.0.borrow_mut().register(entry, data)
+    }
+
+    // Whether there's room to register another operation. Once the kernel
+    // stops taking entries, only so many are queued up for it, so futures
+    // wait for room rather than queueing entries without limit.
+    pub fn has_room(&self) -> bool {
+        self.0.borrow().has_room()
+    }
+
+    // Wakes `waker` once there's room to register operations again
+    pub fn wait_for_room(&self, waker: &Waker) {
+        let mut inner = self.0.borrow_mut();
+        if !inner.room_waiters.iter().any(|w| w.will_wake(waker)) {
+            inner.room_waiters.push(waker.clone());
+        }
+    }
+
+    // Asks the kernel to cancel an operation. The cancellation's own
+    // completion is skipped, and it doesn't wait for room, since there's
+    // nothing to poll it.
+    pub fn cancel(&self, user_data: u64) {
+        let entry = opcode::AsyncCancel::new(user_data)
+            .build()
+            .user_data(u64::MAX);
+        self.0.borrow_mut().push(entry);
+    }
+
+    // Returns the operation's result if it has completed, and otherwise
+    // stores the waker to wake when it does. The result can only be taken
+    // once, after which the user_data may be reused.
+    pub fn poll(&self, user_data: u64, cx: &mut Context) -> Poll<i32> {
+        let mut inner = self.0.borrow_mut();
+        let operation = &mut inner.operations[user_data as usize];
+        match &mut operation.lifecycle {
+            Lifecycle::Completed(ret) => {
+                let ret = *ret;
+                let operation = inner.operations.remove(user_data as usize);
+                drop(inner);
+                drop(operation);
+                Poll::Ready(ret)
+            }
+            // Keep waiting, and make sure the most recent waker is the
+            // one that gets woken (the future may have been polled again
+            // before completing, for example by `select`)
+            Lifecycle::Waiting(waker) if waker.will_wake(cx.waker()) => Poll::Pending,
+            lifecycle => {
+                let previous = mem::replace(lifecycle, Lifecycle::Waiting(cx.waker().clone()));
+                drop(inner);
+                drop(previous);
+                Poll::Pending
+            }
+        }
+    }
+
+    // Called when an operation's future is dropped before taking its result.
+    // An operation that is still in flight stays in the reactor until it
+    // completes, since the kernel may still be using its data.
+    pub fn forget(&self, user_data: u64) {
+        let mut inner = self.0.borrow_mut();
+        let operation = &mut inner.operations[user_data as usize];
+        // Wakers and data are dropped after releasing the reactor, since
+        // they can run arbitrary code when dropped
+        if let Lifecycle::Completed(_) = operation.lifecycle {
+            let operation = inner.operations.remove(user_data as usize);
+            drop(inner);
+            drop(operation);
+        } else {
+            let previous = mem::replace(&mut operation.lifecycle, Lifecycle::Ignored);
+            drop(inner);
+            drop(previous);
+        }
+    }
+
+    // Adds a timer to the reactor's wheel, returning its key. The waker is
+    // woken once the deadline has passed.
+    pub fn insert_timer(&self, deadline: Instant, waker: Waker) -> usize {
+        self.0.borrow_mut().timers.insert(deadline, waker)
+    }
+
+    pub fn reset_timer(&self, key: usize, deadline: Instant) {
+        self.0.borrow_mut().timers.reset(key, deadline);
+    }
+
+    pub fn set_timer_waker(&self, key: usize, waker: &Waker) {
+        let previous = self.0.borrow_mut().timers.set_waker(key, waker);
+        drop(previous);
+    }
+
+    pub fn remove_timer(&self, key: usize) {
+        let waker = self.0.borrow_mut().timers.remove(key);
+        drop(waker);
+    }
+}
+
+/// The state of an operation registered with the reactor. These are stored
+/// in the reactor's slab, keyed by the user_data of the operation's entry.
+enum Lifecycle {
+    /// In flight, and its future hasn't been polled yet
+    Submitted,
+    /// In flight, and its future is waiting to be woken
+    Waiting(Waker),
+    /// Completed with this result, which its future hasn't taken yet
+    Completed(i32),
+    /// In flight, but its future has been dropped
+    Ignored,
+}
+
+struct Operation {
+    lifecycle: Lifecycle,
+    // Memory the kernel uses while the operation is in flight, which has to
+    // outlive the future if it's dropped early. This doesn't allocate for
+    // operations without any data.
+    _data: Box<dyn Any>,
+}
+
+// user_data of the read on the `Notifier`'s eventfd, which completes when the
+// reactor is woken from another thread
+const NOTIFY_USER_DATA: u64 = u64::MAX - 1;
+
+/// Wakes a `Reactor` that is blocked waiting for completions. Tasks woken from
+/// other threads use this so they don't wait on unrelated I/O to be polled.
+pub(crate) struct Notifier {
+    eventfd: RawFd,
+    notified: AtomicBool,
+}
+
+impl Notifier {
+    fn new() -> io::Result<Notifier> {
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if eventfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Notifier {
+            eventfd,
+            notified: AtomicBool::new(false),
+        })
+    }
+
+    /// Wakes the reactor, if it hasn't already been woken since the last
+    /// call to `clear`. This can be called from any thread.
+    pub fn notify(&self) {
+        if self.notified.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let value: u64 = 1;
+        let ret = unsafe {
+            libc::write(
+                self.eventfd,
+                &value as *const u64 as *const libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if ret < 0 {
+            error!("Error notifying reactor: {}", io::Error::last_os_error());
+        }
+    }
+
+    /// Called before checking for the work that `notify` signals, so that
+    /// anything added after the check wakes the reactor again.
+    pub fn clear(&self) {
+        self.notified.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.eventfd);
+        }
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum IouError {
-    #[error("Error submitting event to submission queue {0}")]
-    Push(#[from] PushError),
     #[error("IO Error: {0}")]
     Io(#[from] io::Error),
 }
 
-pub(crate) type Callback = Box<dyn FnOnce(i32) + Send + 'static>;
-
 pub(crate) struct Reactor(Rc<RefCell<Inner>>);
 
+// Not exported by the io-uring crate
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+/// How the reactor's io-uring instance is set up (see `RuntimeBuilder`).
+#[derive(Clone, Debug)]
+pub(crate) struct RingConfig {
+    pub entries: u32,
+    pub cq_entries: Option<u32>,
+    pub sqpoll_idle: Option<Duration>,
+    pub sqpoll_cpu: Option<u32>,
+    pub coop_taskrun: bool,
+    pub single_issuer: bool,
+    pub defer_taskrun: bool,
+}
+
+impl Default for RingConfig {
+    fn default() -> RingConfig {
+        RingConfig {
+            // TODO make the io uring larger
+            entries: 8,
+            cq_entries: None,
+            sqpoll_idle: None,
+            sqpoll_cpu: None,
+            coop_taskrun: false,
+            single_issuer: false,
+            defer_taskrun: false,
+        }
+    }
+}
+
+impl RingConfig {
+    fn build(&self) -> io::Result<IoUring> {
+        let mut builder = IoUring::builder();
+        if let Some(cq_entries) = self.cq_entries {
+            builder.setup_cqsize(cq_entries);
+        }
+        if let Some(idle) = self.sqpoll_idle {
+            builder.setup_sqpoll(idle.as_millis().try_into().unwrap_or(u32::MAX));
+            if let Some(cpu) = self.sqpoll_cpu {
+                builder.setup_sqpoll_cpu(cpu);
+            }
+        }
+        if self.coop_taskrun {
+            builder.setup_coop_taskrun();
+        }
+        // The kernel requires this for DEFER_TASKRUN
+        if self.single_issuer || self.defer_taskrun {
+            builder.setup_single_issuer();
+        }
+        if self.defer_taskrun {
+            builder.setup_defer_taskrun();
+        }
+        builder.build(self.entries)
+    }
+}
+
+// Source of `Inner::id`
+static NEXT_REACTOR_ID: AtomicU64 = AtomicU64::new(0);
+
 struct Inner {
+    id: u64,
     iouring: IoUring,
-    events: HashMap<u64, Callback>,
-    user_data: u64,
-    receiver: Receiver<(Entry, Callback)>,
+    // Operations that have been registered and whose results haven't been
+    // taken. This is declared after the ring so that the operations' data
+    // outlives it.
+    operations: Slab<Operation>,
+    // The number of operations the kernel hasn't completed yet
+    in_flight: usize,
+    // A kernel thread consumes the submission queue
+    sqpoll: bool,
+    // The kernel only posts completions when the reactor asks for them
+    defer_taskrun: bool,
+    // The last value of the completion queue's counter of dropped entries
+    dropped_completions: u32,
+    metrics: Arc<RuntimeMetrics>,
+    // Entries that didn't fit in the submission queue because the kernel
+    // wasn't accepting more, in the order they were registered. Futures
+    // stop registering operations once there are `max_unsubmitted` of them,
+    // though the reactor's own entries are always queued.
+    unsubmitted: VecDeque<Entry>,
+    max_unsubmitted: usize,
+    // Wakers of futures waiting for room in `unsubmitted`
+    room_waiters: Vec<Waker>,
+    notifier: Arc<Notifier>,
+    timers: TimerWheel,
+    // The user_data of the timeout that wakes the reactor for the next
+    // timer, and when it fires
+    timer_timeout: Option<(u64, Instant)>,
+    // The kernel writes the eventfd's counter here. This is declared after
+    // the ring so that it outlives any read that's still in flight.
+    notify_buf: Box<u64>,
 }
 
-impl Reactor {
-    pub fn new() -> Result<(Reactor, ReactorSender), IouError> {
-        // TODO make the io uring larger
-        let iouring = IoUring::new(8)?;
-        let events: HashMap<u64, Callback> = HashMap::new();
-        let (tx, receiver) = sync_channel(10);
-
-        let reactor = Reactor(Rc::new(RefCell::new(Inner {
-            receiver,
-            iouring,
-            events,
-            user_data: 0,
-        })));
+impl Inner {
+    fn register(&mut self, entry: Entry, data: Box<dyn Any>) -> u64 {
+        let user_data = self.operations.insert(Operation {
+            lifecycle: Lifecycle::Submitted,
+            _data: data,
+        }) as u64;
+        self.in_flight += 1;
+        self.push(entry.user_data(user_data));
+        user_data
+    }
 
-        Ok((reactor, tx))
+    fn has_room(&self) -> bool {
+        self.unsubmitted.len() < self.max_unsubmitted
     }
 
-    pub fn tick(&mut self) -> Result<bool, IouError> {
-        let mut inner = self.0.borrow_mut();
-        // TODO do we need to manually drop these?
-
-        while let Ok((mut entry, callback)) = inner.receiver.try_recv() {
-            let user_data = inner.user_data;
-            trace!("submitting entry {} to io uring", user_data);
-            entry = entry.user_data(user_data);
-            unsafe {
-                inner.iouring.submission().push(&entry)?;
+    // Makes sure a timeout is in flight that fires no later than the next
+    // timer is due, so the reactor doesn't sleep through it
+    fn arm_timer(&mut self, now: Instant) {
+        let deadline = self.timers.next_deadline();
+        if let Some((user_data, armed)) = self.timer_timeout {
+            if deadline.is_some_and(|deadline| armed <= deadline) {
+                return;
             }
-            inner.events.insert(user_data, callback);
-            inner.user_data += 1;
-            inner.iouring.submit()?;
+            // Either there are no timers left, or the timeout would fire
+            // too late. The removal's own completion is skipped.
+            let entry = opcode::TimeoutRemove::new(user_data)
+                .build()
+                .user_data(u64::MAX);
+            self.push(entry);
+            self.operations[user_data as usize].lifecycle = Lifecycle::Ignored;
+            self.timer_timeout = None;
+        }
+        if let Some(deadline) = deadline {
+            let duration = deadline.saturating_duration_since(now);
+            // The kernel may read this after the entry is pushed, so it's
+            // kept with the operation
+            let timespec = Box::new(
+                types::Timespec::new()
+                    .sec(duration.as_secs())
+                    .nsec(duration.subsec_nanos()),
+            );
+            let entry = opcode::Timeout::new(&*timespec).build();
+            let user_data = self.register(entry, timespec);
+            self.timer_timeout = Some((user_data, deadline));
         }
+    }
 
-        trace!("reactor has {} events in flight", inner.events.len());
+    // Reads from the notifier's eventfd, which completes the next time the
+    // reactor is notified
+    fn arm_notifier(&mut self) {
+        let buf: *mut u64 = &mut *self.notify_buf;
+        let entry = opcode::Read::new(
+            types::Fd(self.notifier.eventfd),
+            buf.cast(),
+            std::mem::size_of::<u64>() as u32,
+        )
+        .build()
+        .user_data(NOTIFY_USER_DATA);
+        self.push(entry);
+    }
 
-        // If there is at least one entry that's been submitted to io-uring
-        // block this thread until there's a completion queue event
-        // (events is how we track requests that are in-flight)
-        if !inner.events.is_empty() {
-            inner.iouring.submit_and_wait(1)?;
+    // Pushes an entry onto the submission queue. If the queue is full, its
+    // entries are submitted to make room. If the kernel won't take them yet,
+    // the entry waits in `unsubmitted` instead.
+    fn push(&mut self, entry: Entry) {
+        // Entries that didn't fit earlier have to be submitted first
+        if self.unsubmitted.is_empty() {
+            if self.try_push(&entry) {
+                return;
+            }
+            trace!("submission queue is full, submitting to make room");
+            if let Err(err) = self.make_room() {
+                error!("Error submitting entries: {}", err);
+            }
+            if self.try_push(&entry) {
+                return;
+            }
         }
+        debug!("kernel is not accepting entries, queueing entry");
+        self.unsubmitted.push_back(entry);
+    }
 
-        inner.iouring.completion().sync();
-
-        let completed_entries: Vec<(u64, i32)> = inner
-            .iouring
-            .completion()
-            .filter_map(|cqe| {
-                let user_data = cqe.user_data();
+    fn try_push(&mut self, entry: &Entry) -> bool {
+        // Safety: the entry's buffers are kept alive by its operation's data
+        // or future
+        unsafe { self.iouring.submission().push(entry).is_ok() }
+    }
 
-                if user_data == u64::MAX {
-                    debug!("skipped cancelled completion entry.");
-                    return None;
+    // Moves as many unsubmitted entries as the kernel will take into the
+    // submission queue
+    fn flush_unsubmitted(&mut self) -> Result<(), IouError> {
+        while let Some(entry) = self.unsubmitted.front().cloned() {
+            if !self.try_push(&entry) {
+                self.make_room()?;
+                if !self.try_push(&entry) {
+                    break;
                 }
+            }
+            self.unsubmitted.pop_front();
+        }
+        Ok(())
+    }
 
-                Some((user_data, cqe.result()))
-            })
-            .collect();
+    // Submits the entries in the submission queue so there's room for more.
+    // With SQPOLL the kernel thread takes entries off the queue in the
+    // background, so this waits for it to catch up.
+    fn make_room(&mut self) -> Result<(), IouError> {
+        self.submit(0)?;
+        if self.sqpoll && self.iouring.submission().is_full() {
+            self.iouring.submitter().squeue_wait()?;
+        }
+        Ok(())
+    }
 
-        if completed_entries.len() > 0 {
-            trace!("consumed {} entries in 1 tick", completed_entries.len());
+    // Submits the entries in the submission queue and waits for `want`
+    // completions. While the completion queue is too full for more entries
+    // the kernel refuses them with EBUSY. That's not an error: the entries
+    // stay queued, and are submitted again once completions are reaped.
+    //
+    // With SQPOLL this only makes a system call if the kernel thread has gone
+    // to sleep (SQ_NEED_WAKEUP) or the reactor needs to wait.
+    fn submit(&mut self, want: usize) -> Result<(), IouError> {
+        if want == 0 && self.defer_taskrun {
+            // Completions are only posted when the reactor enters the kernel
+            // asking for them, which `submit_and_wait` only does to wait
+            return self.get_events();
         }
+        ignore_busy(self.iouring.submit_and_wait(want))
+    }
 
-        for (user_data, ret) in completed_entries {
-            trace!("got completion for entry {}: {}", user_data, ret);
+    // Submits the entries in the submission queue and has the kernel post
+    // any completions it's holding on to, without waiting
+    fn get_events(&mut self) -> Result<(), IouError> {
+        let to_submit = self.iouring.submission().len() as u32;
+        ignore_busy(unsafe {
+            self.iouring.submitter().enter::<libc::sigset_t>(
+                to_submit,
+                0,
+                IORING_ENTER_GETEVENTS,
+                None,
+            )
+        })
+    }
+
+    // Called when the completion queue is empty. If more completions
+    // finished than fit in the queue, the kernel holds on to the rest until
+    // the reactor asks for them. Returns true if there may be more
+    // completions to reap.
+    fn check_cq_overflow(&mut self, overflowed: &mut bool) -> Result<bool, IouError> {
+        // Completions that the kernel couldn't hold on to are lost for good,
+        // which only happens on kernels without IORING_FEAT_NODROP
+        let dropped = self.iouring.completion().overflow();
+        if dropped != self.dropped_completions {
+            let newly_dropped = dropped.wrapping_sub(self.dropped_completions);
+            self.dropped_completions = dropped;
+            error!(
+                "kernel dropped {} completions because the completion queue was full",
+                newly_dropped
+            );
+            self.metrics
+                .dropped_completions
+                .fetch_add(newly_dropped.into(), Ordering::Relaxed);
+        }
+
+        if !self.iouring.submission().cq_overflow() {
+            return Ok(false);
+        }
+        // Only counted once per tick, however many times it takes to flush
+        if !*overflowed {
+            *overflowed = true;
+            warn!(
+                "completion queue overflowed, consider a larger one (it has {} entries)",
+                self.metrics.cq_entries
+            );
+            self.metrics.cq_overflows.fetch_add(1, Ordering::Relaxed);
+        }
+        self.get_events()?;
+        Ok(!self.iouring.completion().is_empty())
+    }
+}
+
+// While the completion queue is too full for more entries the kernel refuses
+// them with EBUSY. That's not an error: the entries stay queued, and are
+// submitted again once completions are reaped.
+fn ignore_busy(result: io::Result<usize>) -> Result<(), IouError> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+            debug!("kernel is busy, deferring submission");
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Counts problems that a `Runtime`'s reactor has run into.
+#[derive(Debug)]
+pub struct RuntimeMetrics {
+    cq_entries: u32,
+    cq_overflows: AtomicU64,
+    dropped_completions: AtomicU64,
+}
+
+impl RuntimeMetrics {
+    /// The number of entries in the completion queue.
+    pub fn cq_entries(&self) -> u32 {
+        self.cq_entries
+    }
+
+    /// The number of ticks in which more operations completed than fit in
+    /// the completion queue. The kernel holds on to the extra completions
+    /// until there's room, so nothing is lost, but it's slower.
+    pub fn cq_overflows(&self) -> u64 {
+        self.cq_overflows.load(Ordering::Relaxed)
+    }
 
-            if let Some(callback) = inner.events.remove(&user_data) {
-                (callback)(ret)
-            } else {
+    /// The number of completions the kernel dropped because the completion
+    /// queue was full, which only happens on kernels without
+    /// IORING_FEAT_NODROP. The operations they belonged to never complete.
+    pub fn dropped_completions(&self) -> u64 {
+        self.dropped_completions.load(Ordering::Relaxed)
+    }
+}
+
+impl Reactor {
+    pub fn new(config: &RingConfig) -> io::Result<(Reactor, ReactorHandle)> {
+        let iouring = config.build()?;
+        debug!(
+            "created io uring with {} submission and {} completion entries",
+            iouring.params().sq_entries(),
+            iouring.params().cq_entries()
+        );
+
+        let mut inner = Inner {
+            id: NEXT_REACTOR_ID.fetch_add(1, Ordering::Relaxed),
+            sqpoll: iouring.params().is_setup_sqpoll(),
+            defer_taskrun: config.defer_taskrun,
+            dropped_completions: 0,
+            metrics: Arc::new(RuntimeMetrics {
+                cq_entries: iouring.params().cq_entries(),
+                cq_overflows: AtomicU64::new(0),
+                dropped_completions: AtomicU64::new(0),
+            }),
+            timers: TimerWheel::new(Instant::now()),
+            timer_timeout: None,
+            operations: Slab::new(),
+            in_flight: 0,
+            unsubmitted: VecDeque::new(),
+            max_unsubmitted: iouring.params().sq_entries() as usize,
+            room_waiters: Vec::new(),
+            notifier: Arc::new(Notifier::new()?),
+            notify_buf: Box::new(0),
+            iouring,
+        };
+        inner.arm_notifier();
+        let inner = Rc::new(RefCell::new(inner));
+
+        Ok((Reactor(inner.clone()), ReactorHandle(inner)))
+    }
+
+    /// The handle used to wake this reactor from other threads.
+    pub fn notifier(&self) -> Arc<Notifier> {
+        self.0.borrow().notifier.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<RuntimeMetrics> {
+        self.0.borrow().metrics.clone()
+    }
+
+    /// Whether any operations are waiting on the kernel.
+    pub fn has_in_flight(&self) -> bool {
+        self.0.borrow().in_flight > 0
+    }
+
+    // Submits new entries and wakes the futures of completed ones and of
+    // timers that are due. If `wait` is true, this blocks until something
+    // completes, a timer is due or the reactor is notified. All of the
+    // entries registered since the last tick are submitted with the same
+    // system call.
+    pub fn tick(&mut self, wait: bool) -> Result<bool, IouError> {
+        let mut inner = self.0.borrow_mut();
+        inner.flush_unsubmitted()?;
+        // Futures waiting for room are woken once the kernel has taken
+        // enough of the queued entries
+        let room = if inner.has_room() {
+            mem::take(&mut inner.room_waiters)
+        } else {
+            Vec::new()
+        };
+        let now = Instant::now();
+        inner.arm_timer(now);
+        let timer_due = inner.timer_timeout.is_some_and(|(_, due)| due <= now);
+
+        trace!("reactor has {} events in flight", inner.in_flight);
+
+        // Block this thread until there's a completion queue event. The read
+        // on the notifier's eventfd is always in flight, so this also returns
+        // when a task is woken from another thread.
+        if wait && !timer_due {
+            inner.submit(1)?;
+        } else {
+            inner.submit(0)?;
+        }
+        drop(inner);
+        for waker in room {
+            waker.wake();
+        }
+
+        // Completions are reaped one at a time, straight from the completion
+        // queue, so the reactor can be released before waking each future
+        let mut completed = 0;
+        let mut overflowed = false;
+        loop {
+            let mut inner = self.0.borrow_mut();
+            let next = inner.iouring.completion().next();
+            let cqe = match next {
+                Some(cqe) => cqe,
+                None if inner.check_cq_overflow(&mut overflowed)? => continue,
+                None => break,
+            };
+            let user_data = cqe.user_data();
+            let ret = cqe.result();
+
+            if user_data == u64::MAX {
+                debug!("skipped cancelled completion entry.");
+                continue;
+            }
+            if user_data == NOTIFY_USER_DATA {
+                trace!("reactor was notified");
+                inner.arm_notifier();
+                continue;
+            }
+
+            trace!("got completion for entry {}: {}", user_data, ret);
+            completed += 1;
+            if !inner.operations.contains(user_data as usize) {
                 error!(
                     "got completion event from unknown submission: {}",
                     user_data
                 );
+                continue;
+            }
+            inner.in_flight -= 1;
+            if inner.timer_timeout.map(|(timer, _)| timer) == Some(user_data) {
+                // The timers it was for are fired below
+                inner.timer_timeout = None;
+                inner.operations.remove(user_data as usize);
+                continue;
             }
+            let operation = &mut inner.operations[user_data as usize];
+            match mem::replace(&mut operation.lifecycle, Lifecycle::Completed(ret)) {
+                Lifecycle::Submitted => {}
+                Lifecycle::Waiting(waker) => {
+                    drop(inner);
+                    waker.wake();
+                }
+                Lifecycle::Ignored => {
+                    let operation = inner.operations.remove(user_data as usize);
+                    drop(inner);
+                    drop(operation);
+                }
+                Lifecycle::Completed(_) => {
+                    error!("got a second completion for entry {}", user_data);
+                }
+            }
+        }
+
+        if completed > 0 {
+            trace!("consumed {} entries in 1 tick", completed);
+        }
+
+        let woken = self.0.borrow_mut().timers.process(Instant::now());
+        if !woken.is_empty() {
+            trace!("{} timers fired", woken.len());
+        }
+        for waker in woken {
+            waker.wake();
         }
 
-        Ok(inner.events.len() > 0)
+        Ok(self.0.borrow().in_flight > 0)
     }
 }
@@ -0,0 +1,253 @@
+use http::header::{HeaderName, CONTENT_TYPE};
+use http::{HeaderMap, HeaderValue, Response, StatusCode};
+use std::convert::TryFrom;
+use tracing::error;
+
+/// Types that handlers can return, which are converted into responses.
+///
+/// ```ignore
+/// let router = Router::new()
+///     .get("/", |_request: Request<&[u8]>| async { "hello world" })
+///     .post("/users", |_request: Request<&[u8]>| async {
+///         (StatusCode::CREATED, [("location", "/users/1")], "created")
+///     });
+/// ```
+pub trait IntoResponse {
+    fn into_response(self) -> Response<Vec<u8>>;
+}
+
+impl<B: Into<Vec<u8>>> IntoResponse for Response<B> {
+    fn into_response(self) -> Response<Vec<u8>> {
+        self.map(Into::into)
+    }
+}
+
+/// An empty 200 response.
+impl IntoResponse for () {
+    fn into_response(self) -> Response<Vec<u8>> {
+        Response::new(Vec::new())
+    }
+}
+
+impl IntoResponse for &str {
+    fn into_response(self) -> Response<Vec<u8>> {
+        self.to_string().into_response()
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response<Vec<u8>> {
+        with_content_type(self.into_bytes(), "text/plain; charset=utf-8")
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response<Vec<u8>> {
+        with_content_type(self, "application/octet-stream")
+    }
+}
+
+/// A response with the given status and an empty body.
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response<Vec<u8>> {
+        let mut response = Response::new(Vec::new());
+        *response.status_mut() = self;
+        response
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response<Vec<u8>> {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
+/// Overrides the status of the response.
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Response<Vec<u8>> {
+        let (status, value) = self;
+        let mut response = value.into_response();
+        *response.status_mut() = status;
+        response
+    }
+}
+
+/// Adds the headers to the response, replacing any with the same name.
+impl<T: IntoResponse> IntoResponse for (HeaderMap, T) {
+    fn into_response(self) -> Response<Vec<u8>> {
+        let (headers, value) = self;
+        let mut response = value.into_response();
+        response.headers_mut().extend(headers);
+        response
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (StatusCode, HeaderMap, T) {
+    fn into_response(self) -> Response<Vec<u8>> {
+        let (status, headers, value) = self;
+        (status, (headers, value)).into_response()
+    }
+}
+
+/// Adds the headers to the response, replacing any with the same name. If a
+/// header name or value is invalid, the response is a 500 instead.
+impl<K, V, T, const N: usize> IntoResponse for ([(K, V); N], T)
+where
+    HeaderName: TryFrom<K>,
+    <HeaderName as TryFrom<K>>::Error: Into<http::Error>,
+    HeaderValue: TryFrom<V>,
+    <HeaderValue as TryFrom<V>>::Error: Into<http::Error>,
+    T: IntoResponse,
+{
+    fn into_response(self) -> Response<Vec<u8>> {
+        let (pairs, value) = self;
+        let mut headers = HeaderMap::with_capacity(N);
+        for (name, value) in pairs {
+            let header = HeaderName::try_from(name)
+                .map_err(Into::into)
+                .and_then(|name| Ok((name, HeaderValue::try_from(value).map_err(Into::into)?)));
+            match header {
+                Ok((name, value)) => {
+                    headers.append(name, value);
+                }
+                Err(err) => {
+                    error!("Invalid response header: {}", err);
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+        }
+        (headers, value).into_response()
+    }
+}
+
+impl<K, V, T, const N: usize> IntoResponse for (StatusCode, [(K, V); N], T)
+where
+    ([(K, V); N], T): IntoResponse,
+{
+    fn into_response(self) -> Response<Vec<u8>> {
+        let (status, headers, value) = self;
+        (status, (headers, value)).into_response()
+    }
+}
+
+fn with_content_type(body: Vec<u8>, content_type: &'static str) -> Response<Vec<u8>> {
+    let mut response = Response::new(body);
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_type(response: &Response<Vec<u8>>) -> Option<&str> {
+        response
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn strings_are_plain_text() {
+        let owned = String::from("hello");
+        for response in [
+            owned.as_str().into_response(),
+            owned.clone().into_response(),
+        ] {
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(content_type(&response), Some("text/plain; charset=utf-8"));
+            assert_eq!(response.body(), b"hello");
+        }
+    }
+
+    #[test]
+    fn bytes_are_octet_stream() {
+        let response = vec![1, 2, 3].into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(content_type(&response), Some("application/octet-stream"));
+        assert_eq!(response.body(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn status_code_has_empty_body() {
+        let response = StatusCode::NOT_FOUND.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(content_type(&response), None);
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn status_overrides_the_inner_status() {
+        let response = (StatusCode::CREATED, "created").into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(content_type(&response), Some("text/plain; charset=utf-8"));
+        assert_eq!(response.body(), b"created");
+
+        let response = (StatusCode::OK, StatusCode::NOT_FOUND).into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn result_uses_either_side() {
+        let ok: Result<&str, StatusCode> = Ok("fine");
+        let response = ok.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), b"fine");
+
+        let err: Result<&str, (StatusCode, &str)> = Err((StatusCode::BAD_REQUEST, "bad"));
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.body(), b"bad");
+    }
+
+    #[test]
+    fn header_pairs_replace_headers_with_the_same_name() {
+        let response = (
+            StatusCode::CREATED,
+            [("content-type", "text/html"), ("location", "/users/1")],
+            "<p>created</p>",
+        )
+            .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(content_type(&response), Some("text/html"));
+        assert_eq!(response.headers().get_all(CONTENT_TYPE).iter().count(), 1);
+        assert_eq!(response.headers()["location"], "/users/1");
+        assert_eq!(response.body(), b"<p>created</p>");
+    }
+
+    #[test]
+    fn header_map_replaces_headers_with_the_same_name() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/csv"));
+        let response = (StatusCode::ACCEPTED, headers, "a,b").into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(content_type(&response), Some("text/csv"));
+        assert_eq!(response.body(), b"a,b");
+    }
+
+    #[test]
+    fn invalid_header_is_a_server_error() {
+        let response = ([("bad header", "value")], "hello").into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(response.body().is_empty());
+
+        let response = ([("x-ok", "bad\nvalue")], "hello").into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_is_serialized() {
+        use crate::extract::Json;
+
+        let response = (StatusCode::CREATED, Json(vec![("id", 1)])).into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(content_type(&response), Some("application/json"));
+        assert_eq!(response.body(), br#"[["id",1]]"#);
+    }
+}
@@ -0,0 +1,592 @@
+use crate::body::RequestBody;
+use crate::handler::{BoxHandler, Handler, HandlerExt, StreamingCall};
+use futures::future::{FutureExt, LocalBoxFuture};
+use http::header::{ALLOW, LOCATION};
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use std::cmp::Ordering;
+use tracing::trace;
+
+/// Parameters extracted from the request path, available in the request's
+/// extensions when a request is dispatched by a `Router`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Params(pub(crate) Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// How the router treats a trailing slash that doesn't match the route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// `/users/` and `/users` are different paths.
+    Strict,
+    /// A trailing slash is ignored when matching.
+    Ignore,
+    /// Requests are redirected to the path the route was declared with.
+    Redirect,
+}
+
+// Segments are declared in increasing order of how general they are, which
+// is used to pick the most specific route when several match
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Segment {
+    Static(String),
+    Param(String),
+    Wildcard(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    trailing_slash: bool,
+    handler: BoxHandler,
+}
+
+impl Route {
+    // A wildcard matches the rest of the path, including any trailing slash
+    fn matches_trailing_slash(&self, has_trailing_slash: bool) -> bool {
+        matches!(self.segments.last(), Some(Segment::Wildcard(_)))
+            || self.trailing_slash == has_trailing_slash
+    }
+}
+
+/// Dispatches requests to handlers based on the method and path.
+///
+/// Path patterns are made of `/` separated segments, where `:name` matches a
+/// single segment and `*name` (which must be last) matches the rest of the
+/// path. The matched values are available through the `Params` extension.
+///
+/// ```ignore
+/// let router = Router::new()
+///     .get("/users/:id", |request: Request<&[u8]>| {
+///         let id = request.extensions().get::<Params>().unwrap().get("id").unwrap().to_string();
+///         async move { Response::new(id.into_bytes()) }
+///     });
+/// server.serve(router);
+/// ```
+///
+/// Requests for paths that don't match any route get a 404, and requests for
+/// paths that only match routes with other methods get a 405.
+pub struct Router {
+    routes: Vec<Route>,
+    trailing_slash: TrailingSlash,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            trailing_slash: TrailingSlash::Strict,
+        }
+    }
+
+    pub fn trailing_slash(mut self, policy: TrailingSlash) -> Router {
+        self.trailing_slash = policy;
+        self
+    }
+
+    /// Adds a route for requests with the given method and path pattern.
+    ///
+    /// Panics if the pattern is invalid.
+    pub fn route<H: Handler>(mut self, method: Method, pattern: &str, handler: H) -> Router {
+        let segments = parse_pattern(pattern);
+        self.routes.push(Route {
+            method,
+            segments,
+            trailing_slash: pattern.len() > 1 && pattern.ends_with('/'),
+            handler: handler.boxed(),
+        });
+        self
+    }
+
+    pub fn get<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::GET, pattern, handler)
+    }
+
+    pub fn post<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::POST, pattern, handler)
+    }
+
+    pub fn put<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::PUT, pattern, handler)
+    }
+
+    pub fn patch<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::PATCH, pattern, handler)
+    }
+
+    pub fn delete<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::DELETE, pattern, handler)
+    }
+}
+
+// Where the router sends a request: either to a route, or straight back to
+// the client with a redirect or an error
+enum Dispatch<'a> {
+    Route(&'a BoxHandler, Params),
+    Respond(Response<Vec<u8>>),
+}
+
+impl Router {
+    fn dispatch<B>(&self, request: &Request<B>) -> Dispatch<'_> {
+        let path = request.uri().path();
+        let has_trailing_slash = path.len() > 1 && path.ends_with('/');
+
+        // Find the most specific route whose pattern matches the path
+        let mut best: Option<(&Route, Params)> = None;
+        let mut allowed: Vec<Method> = Vec::new();
+        for route in self.routes.iter() {
+            let params = match match_path(&route.segments, path) {
+                Some(params) => params,
+                None => continue,
+            };
+            if self.trailing_slash == TrailingSlash::Strict
+                && !route.matches_trailing_slash(has_trailing_slash)
+            {
+                continue;
+            }
+
+            // HEAD requests are handled by GET routes unless there is a HEAD route
+            let method_matches = route.method == request.method()
+                || (request.method() == Method::HEAD && route.method == Method::GET);
+            if !method_matches {
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method.clone());
+                }
+                continue;
+            }
+
+            let is_better = match &best {
+                Some((current, _)) => match route.segments.cmp(&current.segments) {
+                    Ordering::Less => true,
+                    // A HEAD route wins over a GET route for the same path
+                    Ordering::Equal => route.method == request.method(),
+                    Ordering::Greater => false,
+                },
+                None => true,
+            };
+            if is_better {
+                best = Some((route, params));
+            }
+        }
+
+        match best {
+            Some((route, _))
+                if self.trailing_slash == TrailingSlash::Redirect
+                    && !route.matches_trailing_slash(has_trailing_slash) =>
+            {
+                let location = match redirect_location(request, route.trailing_slash) {
+                    Some(location) => location,
+                    None => {
+                        trace!("not redirecting {} to another host", path);
+                        return not_found();
+                    }
+                };
+                trace!("redirecting {} to {}", path, location);
+                Dispatch::Respond(
+                    Response::builder()
+                        .status(StatusCode::PERMANENT_REDIRECT)
+                        .header(LOCATION, location)
+                        .body(Vec::new())
+                        .unwrap(),
+                )
+            }
+            Some((route, params)) => Dispatch::Route(&route.handler, params),
+            None if !allowed.is_empty() => {
+                trace!("no route for {} {}", request.method(), path);
+                if allowed.contains(&Method::GET) && !allowed.contains(&Method::HEAD) {
+                    allowed.push(Method::HEAD);
+                }
+                let allow = allowed
+                    .iter()
+                    .map(Method::as_str)
+                    .collect::<Vec<&str>>()
+                    .join(", ");
+                Dispatch::Respond(
+                    Response::builder()
+                        .status(StatusCode::METHOD_NOT_ALLOWED)
+                        .header(ALLOW, HeaderValue::from_str(&allow).unwrap())
+                        .body("Method Not Allowed".as_bytes().to_vec())
+                        .unwrap(),
+                )
+            }
+            None => {
+                trace!("no route for {}", path);
+                not_found()
+            }
+        }
+    }
+}
+
+fn not_found<'a>() -> Dispatch<'a> {
+    Dispatch::Respond(
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("Not Found".as_bytes().to_vec())
+            .unwrap(),
+    )
+}
+
+impl Handler for Router {
+    type Future = LocalBoxFuture<'static, Response<Vec<u8>>>;
+
+    fn call(&self, mut request: Request<&[u8]>) -> Self::Future {
+        match self.dispatch(&request) {
+            Dispatch::Route(handler, params) => {
+                request.extensions_mut().insert(params);
+                handler.call(request)
+            }
+            Dispatch::Respond(response) => async move { response }.boxed_local(),
+        }
+    }
+
+    // Requests that would be redirected or get an error don't need their body
+    fn check_continue(&self, request: &Request<()>) -> Option<Response<Vec<u8>>> {
+        match self.dispatch(request) {
+            Dispatch::Route(handler, _) => handler.check_continue(request),
+            Dispatch::Respond(response) => Some(response),
+        }
+    }
+
+    fn streaming(&self, request: &Request<()>) -> Option<StreamingCall> {
+        match self.dispatch(request) {
+            Dispatch::Route(handler, params) => {
+                let call = handler.streaming(request)?;
+                Some(Box::new(move |mut request: Request<RequestBody>| {
+                    request.extensions_mut().insert(params);
+                    call(request)
+                }))
+            }
+            // Requests that would be redirected or get an error are buffered
+            Dispatch::Respond(_) => None,
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(
+        pattern.starts_with('/'),
+        "route pattern must start with a slash: {}",
+        pattern
+    );
+    let segments: Vec<Segment> = pattern
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Static(segment.to_string())
+            }
+        })
+        .collect();
+    let last = segments.len().saturating_sub(1);
+    assert!(
+        !segments[..last]
+            .iter()
+            .any(|segment| matches!(segment, Segment::Wildcard(_))),
+        "a wildcard can only be the last segment of a route pattern: {}",
+        pattern
+    );
+    segments
+}
+
+// Only a single leading slash, and a single trailing slash (which is checked
+// separately, see `TrailingSlash`), are ignored. Empty segments in between
+// don't match anything except a wildcard, so `//host` can't match `/:name`.
+fn match_path(segments: &[Segment], path: &str) -> Option<Params> {
+    let mut params = Vec::new();
+    let mut rest = path.strip_prefix('/')?;
+    rest = rest.strip_suffix('/').unwrap_or(rest);
+
+    for segment in segments {
+        if let Segment::Wildcard(name) = segment {
+            params.push((name.clone(), percent_decode(rest)?));
+            return Some(Params(params));
+        }
+
+        if rest.is_empty() {
+            return None;
+        }
+        let (part, remaining) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index + 1..]),
+            None => (rest, ""),
+        };
+        rest = remaining;
+
+        match segment {
+            Segment::Static(expected) if part == expected => {}
+            Segment::Param(name) if !part.is_empty() => {
+                params.push((name.clone(), percent_decode(part)?))
+            }
+            _ => return None,
+        }
+    }
+
+    if rest.is_empty() {
+        Some(Params(params))
+    } else {
+        None
+    }
+}
+
+// Returns None if the location would be taken as a URL on another host:
+// browsers treat `//host` and `/\host` as relative to the scheme only
+fn redirect_location<B>(request: &Request<B>, trailing_slash: bool) -> Option<String> {
+    let path = request.uri().path();
+    let mut location = if trailing_slash {
+        format!("{}/", path)
+    } else {
+        path.strip_suffix('/').unwrap_or(path).to_string()
+    };
+    if location.starts_with("//") || location.starts_with("/\\") || !location.starts_with('/') {
+        return None;
+    }
+    if let Some(query) = request.uri().query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    Some(location)
+}
+
+// Decodes %XX escapes, returning None if the result isn't valid UTF-8
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' {
+            input
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::{self, BodySource};
+    use futures::executor::block_on;
+    use futures::future;
+    use futures::stream::TryStreamExt;
+
+    // A handler that responds with its own name, so tests can tell which
+    // route a request was dispatched to
+    fn named(name: &'static str) -> impl Handler {
+        move |_: Request<&[u8]>| async move { Response::new(name.as_bytes().to_vec()) }
+    }
+
+    fn send(router: &Router, method: Method, uri: &str) -> Response<Vec<u8>> {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(&[][..])
+            .unwrap();
+        block_on(router.call(request))
+    }
+
+    fn get(router: &Router, uri: &str) -> Response<Vec<u8>> {
+        send(router, Method::GET, uri)
+    }
+
+    fn body(response: Response<Vec<u8>>) -> String {
+        assert_eq!(response.status(), StatusCode::OK);
+        String::from_utf8(response.into_body()).unwrap()
+    }
+
+    fn params(router: &Router, uri: &str) -> Params {
+        let request = Request::get(uri).body(()).unwrap();
+        match router.dispatch(&request) {
+            Dispatch::Route(_, params) => params,
+            Dispatch::Respond(response) => panic!("{} got {}", uri, response.status()),
+        }
+    }
+
+    #[test]
+    fn matches_static_paths() {
+        let router = Router::new()
+            .get("/", named("root"))
+            .get("/users", named("users"))
+            .get("/users/new", named("new"));
+        assert_eq!(body(get(&router, "/")), "root");
+        assert_eq!(body(get(&router, "/users")), "users");
+        assert_eq!(body(get(&router, "/users/new")), "new");
+        assert_eq!(get(&router, "/users/old").status(), StatusCode::NOT_FOUND);
+        assert_eq!(get(&router, "/user").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn extracts_params_and_wildcards() {
+        let router = Router::new()
+            .get("/users/:id/posts/:post", named("post"))
+            .get("/files/*path", named("files"));
+        let matched = params(&router, "/users/42/posts/hello%20world");
+        assert_eq!(matched.get("id"), Some("42"));
+        assert_eq!(matched.get("post"), Some("hello world"));
+        assert_eq!(
+            params(&router, "/files/a/b/c.txt").get("path"),
+            Some("a/b/c.txt")
+        );
+        assert_eq!(params(&router, "/files/").get("path"), Some(""));
+    }
+
+    #[test]
+    fn prefers_static_over_param_over_wildcard() {
+        // Declared from least to most specific, so the order of declaration
+        // can't be what picks the route
+        let router = Router::new()
+            .get("/users/*rest", named("wildcard"))
+            .get("/users/:id", named("param"))
+            .get("/users/me", named("static"));
+        assert_eq!(body(get(&router, "/users/me")), "static");
+        assert_eq!(body(get(&router, "/users/42")), "param");
+        assert_eq!(body(get(&router, "/users/42/posts")), "wildcard");
+    }
+
+    #[test]
+    fn empty_segments_do_not_match_params() {
+        let router = Router::new().get("/:name", named("name"));
+        assert_eq!(get(&router, "//").status(), StatusCode::NOT_FOUND);
+        assert_eq!(get(&router, "//evil.com").status(), StatusCode::NOT_FOUND);
+        let router = Router::new().get("/a/:b/c", named("abc"));
+        assert_eq!(get(&router, "/a//c").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn wrong_method_is_not_allowed() {
+        let router = Router::new()
+            .get("/users", named("list"))
+            .post("/users", named("create"));
+        assert_eq!(body(send(&router, Method::POST, "/users")), "create");
+        let response = send(&router, Method::DELETE, "/users");
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, POST, HEAD");
+        assert_eq!(
+            send(&router, Method::DELETE, "/other").status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = Router::new().get("/", named("get"));
+        assert_eq!(body(send(&router, Method::HEAD, "/")), "get");
+        let router = router.route(Method::HEAD, "/", named("head"));
+        assert_eq!(body(send(&router, Method::HEAD, "/")), "head");
+    }
+
+    #[test]
+    fn trailing_slash_policies() {
+        let routes = || {
+            Router::new()
+                .get("/users", named("users"))
+                .get("/dirs/", named("dirs"))
+        };
+
+        let strict = routes();
+        assert_eq!(get(&strict, "/users/").status(), StatusCode::NOT_FOUND);
+        assert_eq!(get(&strict, "/dirs").status(), StatusCode::NOT_FOUND);
+
+        let ignore = routes().trailing_slash(TrailingSlash::Ignore);
+        assert_eq!(body(get(&ignore, "/users/")), "users");
+        assert_eq!(body(get(&ignore, "/dirs")), "dirs");
+
+        let redirect = routes().trailing_slash(TrailingSlash::Redirect);
+        let response = get(&redirect, "/users/?page=2");
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], "/users?page=2");
+        let response = get(&redirect, "/dirs");
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], "/dirs/");
+        assert_eq!(body(get(&redirect, "/users")), "users");
+    }
+
+    #[test]
+    fn never_redirects_to_another_host() {
+        let router = Router::new()
+            .get("/:name", named("name"))
+            .trailing_slash(TrailingSlash::Redirect);
+        assert_eq!(get(&router, "//evil.com/").status(), StatusCode::NOT_FOUND);
+
+        let router = Router::new()
+            .get("/:a/", named("dir"))
+            .trailing_slash(TrailingSlash::Redirect);
+        assert_eq!(get(&router, "//evil.com").status(), StatusCode::NOT_FOUND);
+
+        // The location is checked as well, in case a route ever matches
+        for path in ["//evil.com/", "///evil.com", "/\\evil.com/"] {
+            let request = Request::get(path).body(()).unwrap();
+            assert_eq!(redirect_location(&request, false), None, "{}", path);
+        }
+    }
+
+    // Streams request bodies, and responds with the `id` path parameter
+    struct Streaming;
+
+    impl Handler for Streaming {
+        type Future = future::Ready<Response<Vec<u8>>>;
+
+        fn call(&self, _request: Request<&[u8]>) -> Self::Future {
+            future::ready(Response::new(b"buffered".to_vec()))
+        }
+
+        fn streaming(&self, _request: &Request<()>) -> Option<StreamingCall> {
+            Some(Box::new(|request: Request<RequestBody>| {
+                let params = request.extensions().get::<Params>().cloned();
+                let id = params.as_ref().and_then(|params| params.get("id"));
+                let id = id.unwrap_or_default().as_bytes().to_vec();
+                async move { Response::new(body::full(id)) }.boxed_local()
+            }))
+        }
+    }
+
+    #[test]
+    fn streams_bodies_of_routes_that_stream_them() {
+        let router = Router::new()
+            .post("/streams/:id", Streaming)
+            .post("/buffers", named("buffers"));
+        let head = |uri| Request::post(uri).body(()).unwrap();
+        assert!(router.streaming(&head("/buffers")).is_none());
+        assert!(router.streaming(&head("/missing")).is_none());
+
+        let call = router.streaming(&head("/streams/7")).unwrap();
+        let request_body = RequestBody::new(Vec::new(), BodySource::new(-1, None, 0));
+        let response = block_on(call(head("/streams/7").map(|()| request_body)));
+        let body = block_on(response.into_body().try_concat()).unwrap();
+        assert_eq!(body, b"7");
+    }
+}
@@ -1,58 +1,198 @@
 use crate::executor::{new_executor_and_spawner, Executor, Spawner};
-use crate::reactor::{Callback, Reactor, ReactorSender};
-use io_uring::squeue::Entry;
+use crate::reactor::{Reactor, ReactorHandle, RingConfig};
+use crate::task::{joinable, JoinHandle};
+
+pub use crate::reactor::RuntimeMetrics;
+use futures::pin_mut;
+use futures::task::waker_ref;
+use std::any::Any;
 use std::cell::RefCell;
 use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::thread_local;
+use std::time::Duration;
 use tracing::trace;
 
+// The largest completion queue the kernel will create (IORING_MAX_CQ_ENTRIES)
+const MAX_CQ_ENTRIES: u32 = 65536;
+
 // TODO should this be scoped thread local storage?
-thread_local!(static RUNTIME: RefCell<Option<(Spawner, ReactorSender)>> = RefCell::new(None));
+thread_local!(static RUNTIME: RefCell<Option<(Spawner, ReactorHandle)>> = const { RefCell::new(None) });
 
-pub fn spawn(future: impl Future<Output = ()> + 'static + Send) {
+/// Spawns a task onto the current thread's runtime, returning a handle that
+/// resolves to the task's output.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static + Send,
+    F::Output: Send,
+{
+    let (task, join_handle) = joinable(future);
     RUNTIME.with(move |handle| match &*handle.borrow() {
-        Some((spawner, _)) => spawner.spawn(future),
+        Some((spawner, _)) => spawner.spawn(task),
         None => panic!("cannot call spawn before creating a runtime"),
-    })
+    });
+    join_handle
 }
 
-pub(crate) fn register(entry: Entry, callback: Callback) {
+/// Spawns a task that doesn't need to be `Send` onto the current thread's
+/// runtime. The task always runs on this thread, so it can hold `Rc`s and
+/// `RefCell` borrows across awaits.
+pub fn spawn_local<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+{
+    let (task, join_handle) = joinable(future);
     RUNTIME.with(move |handle| match &*handle.borrow() {
-        Some((_, reactor_sender)) => reactor_sender.send((entry, callback)).unwrap(),
-        None => panic!("cannot call spawn before creating a runtime"),
+        Some((spawner, _)) => spawner.spawn_local(task),
+        None => panic!("cannot call spawn_local before creating a runtime"),
+    });
+    join_handle
+}
+
+// The reactor of the current thread's runtime, which operations are
+// submitted to
+pub(crate) fn reactor() -> ReactorHandle {
+    try_reactor().expect("cannot submit an operation before creating a runtime")
+}
+
+// Like `reactor`, but returns None if there's no runtime (or the thread is
+// exiting)
+pub(crate) fn try_reactor() -> Option<ReactorHandle> {
+    RUNTIME
+        .try_with(|handle| {
+            let handle = handle.borrow();
+            handle
+                .as_ref()
+                .map(|(_, reactor_handle)| reactor_handle.clone())
+        })
+        .ok()
+        .flatten()
+}
+
+/// Reports a panic caught outside of the executor to the current runtime's
+/// panic hook.
+pub(crate) fn report_panic(payload: &(dyn Any + Send)) {
+    RUNTIME.with(move |handle| match &*handle.borrow() {
+        Some((spawner, _)) => spawner.report_panic(payload),
+        None => panic!("cannot report a panic before creating a runtime"),
     })
 }
 
+/// Configures how a `Runtime`'s io-uring instance is set up.
+///
+/// ```ignore
+/// let mut runtime = RuntimeBuilder::new()
+///     .entries(256)
+///     .sqpoll(Duration::from_millis(10))
+///     .build()?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeBuilder {
+    ring: RingConfig,
+}
+
+impl RuntimeBuilder {
+    pub fn new() -> RuntimeBuilder {
+        RuntimeBuilder::default()
+    }
+
+    /// Sets the number of entries in the submission queue (8 by default).
+    pub fn entries(mut self, entries: u32) -> RuntimeBuilder {
+        self.ring.entries = entries;
+        self
+    }
+
+    /// Sets the number of entries in the completion queue, which can't be
+    /// less than the submission queue's. By default it's twice as large.
+    pub fn cq_entries(mut self, entries: u32) -> RuntimeBuilder {
+        self.ring.cq_entries = Some(entries);
+        self
+    }
+
+    /// Has a kernel thread poll the submission queue (IORING_SETUP_SQPOLL),
+    /// so submitting entries doesn't take a system call. The thread goes to
+    /// sleep after `idle` without any new entries, and the reactor wakes it
+    /// up the next time it submits one.
+    pub fn sqpoll(mut self, idle: Duration) -> RuntimeBuilder {
+        self.ring.sqpoll_idle = Some(idle);
+        self
+    }
+
+    /// Pins the `sqpoll` kernel thread to a CPU.
+    pub fn sqpoll_cpu(mut self, cpu: u32) -> RuntimeBuilder {
+        self.ring.sqpoll_cpu = Some(cpu);
+        self
+    }
+
+    /// Stops the kernel from interrupting the runtime's thread when an
+    /// operation completes (IORING_SETUP_COOP_TASKRUN). Completions are
+    /// processed the next time the thread enters the kernel instead, which
+    /// the reactor does before it looks for them.
+    pub fn coop_taskrun(mut self) -> RuntimeBuilder {
+        self.ring.coop_taskrun = true;
+        self
+    }
+
+    /// Tells the kernel that only the runtime's thread submits entries
+    /// (IORING_SETUP_SINGLE_ISSUER), which lets it skip some locking.
+    pub fn single_issuer(mut self) -> RuntimeBuilder {
+        self.ring.single_issuer = true;
+        self
+    }
+
+    /// Defers processing completions until the reactor asks for them
+    /// (IORING_SETUP_DEFER_TASKRUN). This implies `single_issuer`, and can't
+    /// be combined with `sqpoll`.
+    pub fn defer_taskrun(mut self) -> RuntimeBuilder {
+        self.ring.defer_taskrun = true;
+        self
+    }
+
+    /// Doubles the size of the completion queue if `metrics`, from a runtime
+    /// that was built earlier, show that it overflowed. This is meant for
+    /// restarting a runtime after it has shut down.
+    pub fn grow_after_overflow(mut self, metrics: &RuntimeMetrics) -> RuntimeBuilder {
+        if metrics.cq_overflows() > 0 || metrics.dropped_completions() > 0 {
+            self.ring.cq_entries = Some((metrics.cq_entries() * 2).min(MAX_CQ_ENTRIES));
+        }
+        self
+    }
+
+    /// Creates the runtime. This fails if the kernel doesn't support one of
+    /// the options, or (with `sqpoll`) if the process isn't allowed to use it.
+    pub fn build(self) -> io::Result<Runtime> {
+        Runtime::with_config(&self.ring)
+    }
+}
+
 pub struct Runtime {
     executor: Executor,
     reactor: Reactor,
-    spawner: Option<Spawner>,
 }
 
 impl Runtime {
     pub fn new() -> Runtime {
-        let (reactor, reactor_sender) = Reactor::new().unwrap();
-        let (executor, spawner) = new_executor_and_spawner();
+        RuntimeBuilder::new().build().unwrap()
+    }
+
+    fn with_config(config: &RingConfig) -> io::Result<Runtime> {
+        let (reactor, reactor_handle) = Reactor::new(config)?;
+        let (executor, spawner) = new_executor_and_spawner(reactor.notifier());
 
         // TODO should the thread local be set when the Runtime is created or when a future is spawned?
-        let spawner_clone = spawner.clone();
-        let reactor_sender_clone = reactor_sender.clone();
         RUNTIME.with(move |handle| {
-            handle.replace(Some((spawner_clone, reactor_sender_clone)));
+            handle.replace(Some((spawner, reactor_handle)));
         });
 
-        Runtime {
-            reactor,
-            executor,
-            spawner: Some(spawner),
-        }
+        Ok(Runtime { reactor, executor })
     }
 
+    /// Runs the runtime until every spawned task has finished, including
+    /// tasks spawned while it runs. Tasks waiting on another thread keep it
+    /// running until they're woken and finish, or are dropped.
     pub fn run(&mut self) {
-        // Drop the spawner so that the executor exits when there are no more
-        // references to the spawner
-        drop(self.spawner.take());
-
         while self.tick() {}
     }
 
@@ -63,16 +203,76 @@ impl Runtime {
         // but if neither did anything, that means there are no pending futures
         // and no IO tasks in flight so we should exit.
         let executor_processing = self.executor.tick();
-        let reactor_processing = self.reactor.tick().unwrap();
-        executor_processing || reactor_processing
+        // Tasks that are still pending can be woken by other threads (for
+        // example through a channel), so the reactor waits to be notified
+        // even if they have no I/O in flight
+        let pending_tasks = self.executor.has_pending_tasks();
+        let wait = pending_tasks || self.reactor.has_in_flight();
+        let reactor_processing = self.reactor.tick(wait).unwrap();
+        executor_processing || reactor_processing || pending_tasks
+    }
+
+    /// Counters for problems the reactor has run into, which stay readable
+    /// after the runtime is dropped.
+    pub fn metrics(&self) -> Arc<RuntimeMetrics> {
+        self.reactor.metrics()
+    }
+
+    /// Sets the function that is called with the payload of each panic
+    /// caught from a task (or from a handler, by the `HttpServer`). The task
+    /// that panicked is dropped, and the other tasks keep running.
+    ///
+    /// By default the panic message is logged.
+    pub fn set_panic_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&(dyn Any + Send)) + Send + Sync + 'static,
+    {
+        self.executor.set_panic_hook(Arc::new(hook));
     }
 
-    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static + Send) {
-        spawn(future);
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static + Send,
+        F::Output: Send,
+    {
+        spawn(future)
     }
 
-    pub fn block_on(&mut self, future: impl Future<Output = ()> + 'static + Send) {
-        self.spawn(future);
-        self.run();
+    pub fn spawn_local<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        spawn_local(future)
+    }
+
+    /// Runs the runtime until `future` completes, returning its output.
+    ///
+    /// The future is polled on this thread rather than spawned, so it
+    /// doesn't need to be `Send` or `'static`. Tasks that are still pending
+    /// when it completes stay on the runtime, and carry on running the next
+    /// time it's driven by `block_on` or `run`.
+    pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        let waker = self.executor.block_on_waker();
+        let task_waker = waker_ref(&waker);
+        let mut cx = Context::from_waker(&task_waker);
+        pin_mut!(future);
+        loop {
+            if waker.take_woken() {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+            }
+            self.executor.tick();
+            // Whatever wakes the future (a task, I/O, a timer or another
+            // thread) interrupts the reactor, unless it already has
+            let wait = !waker.is_woken();
+            self.reactor.tick(wait).unwrap();
+        }
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Runtime {
+        Runtime::new()
     }
 }
@@ -13,7 +13,7 @@ pub struct Accept<'a> {
 
 impl<'a> Accept<'a> {
     // TODO does this lifetime need to be static?
-    pub fn submit(socket: &'a TcpListener) -> SysCall<Accept<'a>> {
+    pub fn submit<L: AsRawFd>(socket: &'a L) -> SysCall<Accept<'a>> {
         let entry =
             opcode::Accept::new(Fd(socket.as_raw_fd()), std::ptr::null_mut(), std::ptr::null_mut())
                 .build();
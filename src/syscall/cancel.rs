@@ -0,0 +1,12 @@
+use io_uring::opcode;
+
+use crate::syscall::SysCall;
+
+pub struct Cancel {}
+
+impl Cancel {
+    pub fn submit(user_data: u64) -> SysCall<Cancel> {
+        let entry = opcode::AsyncCancel::new(user_data).build();
+        SysCall::from_entry(entry, Cancel {})
+    }
+}
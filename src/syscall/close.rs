@@ -1,12 +1,11 @@
 use io_uring::{opcode, types::Fd};
-use std::net::TcpStream;
 use std::os::unix::io::IntoRawFd;
 use crate::syscall::SysCall;
 
 pub struct Close{ }
 
 impl Close{
-    pub fn submit(socket: TcpStream) -> SysCall<Close> {
+    pub fn submit(socket: impl IntoRawFd) -> SysCall<Close> {
 		// TODO do we need to make sure the socket isn't dropped
 		// (because Rust will close the socket with a normal syscall
 		// if the value is dropped)
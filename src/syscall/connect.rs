@@ -0,0 +1,24 @@
+use io_uring::{opcode, types::Fd};
+use std::marker::PhantomData;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+
+use crate::syscall::sockaddr::SockAddr;
+use crate::syscall::SysCall;
+
+pub struct Connect<'a> {
+    socket: PhantomData<&'a TcpStream>,
+}
+
+impl<'a> Connect<'a> {
+    pub fn submit(socket: &'a TcpStream, addr: SocketAddr) -> SysCall<Connect<'a>> {
+        // The address is boxed and handed to the SysCall so that it is
+        // still around when the kernel reads it, even if this future is dropped
+        let addr = Box::new(SockAddr::from(addr));
+        let entry = opcode::Connect::new(Fd(socket.as_raw_fd()), addr.as_ptr(), addr.len).build();
+        let future = Connect {
+            socket: PhantomData,
+        };
+        SysCall::from_entry_with_data(entry, future, addr)
+    }
+}
@@ -1,83 +1,152 @@
 use io_uring::squeue::Entry;
+use std::any::Any;
 use std::future::Future;
 use std::io::Error;
 use std::marker::PhantomData;
 use std::mem;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll, Waker};
+use std::task::{ready, Context, Poll};
 
 mod accept;
+mod cancel;
 mod close;
+mod connect;
 mod recv;
+mod recv_msg;
 mod send;
+mod send_msg;
+mod shutdown;
+pub(crate) mod sockaddr;
+mod timeout;
 
 pub use accept::Accept;
+pub use cancel::Cancel;
 pub use close::Close;
+pub use connect::Connect;
 pub use recv::Recv;
+pub use recv_msg::RecvMsg;
 pub use send::Send;
+pub use send_msg::SendMsg;
+pub use shutdown::Shutdown;
+pub use timeout::Timeout;
 
-use crate::runtime::register;
-
-// This represents the possible states of a syscall
-// submitted to io-uring
-pub(crate) enum Lifecycle {
-    Submitted,
-    Waiting(Waker),
-    Completed(i32),
-}
+use crate::runtime::{reactor, try_reactor};
 
+/// A future for an operation submitted to the current thread's io-uring
+/// reactor, which resolves to the operation's result.
+///
+/// The operation's state lives in the reactor, so the future has to be polled
+/// on the thread that created it. If too many operations are already waiting
+/// for the kernel to take them, the operation isn't started until the future
+/// is polled and there's room for it.
 pub struct SysCall<T> {
-    state: Arc<Mutex<Lifecycle>>,
+    registration: Registration,
+    reactor_id: u64,
+    finished: bool,
     kind: PhantomData<T>,
 }
 
+enum Registration {
+    // Waiting for room in the reactor, with the data to hand it
+    Waiting(Entry, Box<dyn Any + std::marker::Send>),
+    Registered(u64),
+    // Cancelled before it was registered, so it never started
+    Cancelled,
+}
+
 impl<T> SysCall<T> {
-    pub fn from_entry(entry: Entry, _kind: T) -> SysCall<T> {
-        let state = Arc::new(Mutex::new(Lifecycle::Submitted));
-        let state_clone = state.clone();
-        register(
-            entry,
-            Box::new(move |n: i32| {
-                let previous_state =
-                    mem::replace(&mut *(state_clone).lock().unwrap(), Lifecycle::Completed(n));
-                if let Lifecycle::Waiting(waker) = previous_state {
-                    waker.wake();
-                }
-            }),
-        );
+    pub fn from_entry(entry: Entry, kind: T) -> SysCall<T> {
+        SysCall::from_entry_with_data(entry, kind, Box::new(()))
+    }
+
+    // Like `from_entry`, but `data` is owned by the reactor until the kernel
+    // completes the operation. Use this for buffers or addresses that the
+    // kernel reads from, so they outlive the future if it is dropped early.
+    // The data is usually boxed already, so that its address is stable, and
+    // the box is handed on rather than boxed again.
+    pub(crate) fn from_entry_with_data(
+        entry: Entry,
+        _kind: T,
+        data: Box<dyn Any + std::marker::Send>,
+    ) -> SysCall<T> {
+        let reactor = reactor();
+        let registration = if reactor.has_room() {
+            Registration::Registered(reactor.register(entry, data))
+        } else {
+            Registration::Waiting(entry, data)
+        };
         SysCall {
-            state,
+            registration,
+            reactor_id: reactor.id(),
+            finished: false,
             kind: PhantomData,
         }
     }
+
+    /// Asks the kernel to cancel the operation. The future still needs to
+    /// be polled to completion and will usually resolve to `ECANCELED`.
+    pub fn cancel(&mut self) {
+        match self.registration {
+            // Once the result has been taken the user_data may be reused by
+            // another operation, which shouldn't be cancelled
+            Registration::Registered(user_data) if !self.finished => reactor().cancel(user_data),
+            Registration::Registered(_) | Registration::Cancelled => {}
+            Registration::Waiting(..) => self.registration = Registration::Cancelled,
+        }
+    }
 }
 
+// The operation's state lives in the reactor, so nothing here is pinned
+impl<T> Unpin for SysCall<T> {}
+
 impl<T> Future for SysCall<T> {
     type Output = Result<u32, Error>;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let previous_state = mem::replace(&mut *(self.state).lock().unwrap(), Lifecycle::Submitted);
-        match previous_state {
-            Lifecycle::Submitted => {
-                // need to submit to the queue
-                *self.state.lock().unwrap() = Lifecycle::Waiting(cx.waker().clone());
-
-                Poll::Pending
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        assert!(!self.finished, "SysCall polled after completion");
+        let reactor = reactor();
+        assert_eq!(
+            reactor.id(),
+            self.reactor_id,
+            "SysCall polled outside of the runtime it was created on"
+        );
+        let user_data = match mem::replace(&mut self.registration, Registration::Cancelled) {
+            Registration::Registered(user_data) => user_data,
+            Registration::Waiting(entry, data) if reactor.has_room() => {
+                reactor.register(entry, data)
+            }
+            Registration::Waiting(entry, data) => {
+                reactor.wait_for_room(cx.waker());
+                self.registration = Registration::Waiting(entry, data);
+                return Poll::Pending;
             }
-            Lifecycle::Waiting(waker) => {
-                if !waker.will_wake(cx.waker()) {
-                    *self.state.lock().unwrap() = Lifecycle::Waiting(cx.waker().clone());
-                }
-                Poll::Pending
+            Registration::Cancelled => {
+                self.finished = true;
+                return Poll::Ready(Err(Error::from_raw_os_error(libc::ECANCELED)));
             }
-            Lifecycle::Completed(ret) => {
-                // todo, replace state with completed
-                if ret >= 0 {
-                    Poll::Ready(Ok(ret as u32))
-                } else {
-                    Poll::Ready(Err(Error::from_raw_os_error(-ret)))
-                }
+        };
+        self.registration = Registration::Registered(user_data);
+        let ret = ready!(reactor.poll(user_data, cx));
+        self.finished = true;
+        if ret >= 0 {
+            Poll::Ready(Ok(ret as u32))
+        } else {
+            Poll::Ready(Err(Error::from_raw_os_error(-ret)))
+        }
+    }
+}
+
+impl<T> Drop for SysCall<T> {
+    fn drop(&mut self) {
+        let user_data = match self.registration {
+            Registration::Registered(user_data) if !self.finished => user_data,
+            // An operation that was never registered has nothing to clean up
+            _ => return,
+        };
+        // If the runtime has already shut down, so has the operation
+        if let Some(reactor) = try_reactor() {
+            if reactor.id() == self.reactor_id {
+                reactor.forget(user_data);
             }
         }
     }
@@ -9,7 +9,7 @@ pub struct Recv<'a> {
 }
 
 impl<'a> Recv<'a> {
-    pub fn submit(buf: &'a mut [u8], stream: &'a mut TcpStream) -> SysCall<Recv<'a>> {
+    pub fn submit<S: AsRawFd>(buf: &'a mut [u8], stream: &'a mut S) -> SysCall<Recv<'a>> {
         let raw_fd = stream.as_raw_fd();
         let entry =
             opcode::Recv::new(Fd(raw_fd), buf.as_mut_ptr(), buf.len() as u32)
@@ -0,0 +1,22 @@
+use io_uring::{opcode, types::Fd};
+use std::marker::PhantomData;
+use std::net::UdpSocket;
+use std::os::unix::io::AsRawFd;
+
+use crate::syscall::SysCall;
+
+pub struct RecvMsg<'a> {
+    socket: PhantomData<&'a UdpSocket>,
+}
+
+impl<'a> RecvMsg<'a> {
+    // The message header, and everything it points to, must stay valid
+    // until the returned SysCall completes
+    pub fn submit(msg: &'a mut libc::msghdr, socket: &'a UdpSocket) -> SysCall<RecvMsg<'a>> {
+        let entry = opcode::RecvMsg::new(Fd(socket.as_raw_fd()), msg).build();
+        let future = RecvMsg {
+            socket: PhantomData,
+        };
+        SysCall::from_entry(entry, future)
+    }
+}
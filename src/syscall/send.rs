@@ -10,9 +10,9 @@ pub struct Send<'a> {
 }
 
 impl<'a> Send<'a> {
-    pub fn submit(buf: &'a mut [u8], stream: &'a mut TcpStream) -> SysCall<Send<'a>> {
+    pub fn submit<S: AsRawFd>(buf: &'a [u8], stream: &'a mut S) -> SysCall<Send<'a>> {
         let raw_fd = stream.as_raw_fd();
-        let entry = opcode::Send::new(Fd(raw_fd), buf.as_mut_ptr(), buf.len() as u32).build();
+        let entry = opcode::Send::new(Fd(raw_fd), buf.as_ptr(), buf.len() as u32).build();
         let future = Send {
             stream: PhantomData,
         };
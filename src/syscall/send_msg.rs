@@ -0,0 +1,22 @@
+use io_uring::{opcode, types::Fd};
+use std::marker::PhantomData;
+use std::net::UdpSocket;
+use std::os::unix::io::AsRawFd;
+
+use crate::syscall::SysCall;
+
+pub struct SendMsg<'a> {
+    socket: PhantomData<&'a UdpSocket>,
+}
+
+impl<'a> SendMsg<'a> {
+    // The message header, and everything it points to, must stay valid
+    // until the returned SysCall completes
+    pub fn submit(msg: &'a libc::msghdr, socket: &'a UdpSocket) -> SysCall<SendMsg<'a>> {
+        let entry = opcode::SendMsg::new(Fd(socket.as_raw_fd()), msg).build();
+        let future = SendMsg {
+            socket: PhantomData,
+        };
+        SysCall::from_entry(entry, future)
+    }
+}
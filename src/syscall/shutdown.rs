@@ -0,0 +1,25 @@
+use io_uring::{opcode, types::Fd};
+use std::marker::PhantomData;
+use std::net::{Shutdown as How, TcpStream};
+use std::os::unix::io::AsRawFd;
+
+use crate::syscall::SysCall;
+
+pub struct Shutdown<'a> {
+    socket: PhantomData<&'a TcpStream>,
+}
+
+impl<'a> Shutdown<'a> {
+    pub fn submit(socket: &'a TcpStream, how: How) -> SysCall<Shutdown<'a>> {
+        let how = match how {
+            How::Read => libc::SHUT_RD,
+            How::Write => libc::SHUT_WR,
+            How::Both => libc::SHUT_RDWR,
+        };
+        let entry = opcode::Shutdown::new(Fd(socket.as_raw_fd()), how).build();
+        let future = Shutdown {
+            socket: PhantomData,
+        };
+        SysCall::from_entry(entry, future)
+    }
+}
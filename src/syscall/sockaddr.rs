@@ -0,0 +1,80 @@
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+// A socket address in the layout the kernel expects. This is boxed by the
+// syscalls that use it so the pointer handed to io-uring stays valid until
+// the operation completes.
+pub(crate) struct SockAddr {
+    pub(crate) storage: libc::sockaddr_storage,
+    pub(crate) len: libc::socklen_t,
+}
+
+impl SockAddr {
+    pub(crate) fn empty() -> SockAddr {
+        SockAddr {
+            storage: unsafe { mem::zeroed() },
+            len: mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
+        }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const libc::sockaddr {
+        &self.storage as *const libc::sockaddr_storage as *const libc::sockaddr
+    }
+
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut libc::sockaddr {
+        &mut self.storage as *mut libc::sockaddr_storage as *mut libc::sockaddr
+    }
+
+    pub(crate) fn as_socket_addr(&self) -> Option<SocketAddr> {
+        match self.storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(self.as_ptr() as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    ip,
+                    u16::from_be(addr.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(self.as_ptr() as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for SockAddr {
+    fn from(addr: SocketAddr) -> SockAddr {
+        let mut sock_addr = SockAddr::empty();
+        match addr {
+            SocketAddr::V4(addr) => {
+                let raw = unsafe { &mut *(sock_addr.as_mut_ptr() as *mut libc::sockaddr_in) };
+                raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                raw.sin_port = addr.port().to_be();
+                raw.sin_addr = libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                };
+                sock_addr.len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            }
+            SocketAddr::V6(addr) => {
+                let raw = unsafe { &mut *(sock_addr.as_mut_ptr() as *mut libc::sockaddr_in6) };
+                raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                raw.sin6_port = addr.port().to_be();
+                raw.sin6_addr = libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                };
+                raw.sin6_flowinfo = addr.flowinfo();
+                raw.sin6_scope_id = addr.scope_id();
+                sock_addr.len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            }
+        }
+        sock_addr
+    }
+}
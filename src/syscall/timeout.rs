@@ -0,0 +1,20 @@
+use io_uring::{opcode, types::Timespec};
+use std::time::Duration;
+
+use crate::syscall::SysCall;
+
+pub struct Timeout {}
+
+impl Timeout {
+    // Resolves to `ETIME` when the timer expires, or to `ECANCELED` if it
+    // is cancelled first
+    pub fn submit(duration: Duration) -> SysCall<Timeout> {
+        let timespec = Box::new(
+            Timespec::new()
+                .sec(duration.as_secs())
+                .nsec(duration.subsec_nanos()),
+        );
+        let entry = opcode::Timeout::new(&*timespec).build();
+        SysCall::from_entry_with_data(entry, Timeout {}, timespec)
+    }
+}
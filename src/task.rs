@@ -0,0 +1,83 @@
+use crate::runtime::report_panic;
+use futures::channel::oneshot;
+use futures::future::{AbortHandle, Abortable, Aborted, Future, FutureExt};
+use std::any::Any;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use thiserror::Error;
+
+/// Why a task didn't finish with an output.
+#[derive(Error)]
+pub enum JoinError {
+    /// The task was aborted, or dropped because its runtime shut down.
+    #[error("task was cancelled")]
+    Cancelled,
+    /// The task panicked. This holds the panic's payload, which can be
+    /// passed to `std::panic::resume_unwind` to propagate the panic.
+    #[error("task panicked")]
+    Panic(Box<dyn Any + Send>),
+}
+
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => f.write_str("Cancelled"),
+            JoinError::Panic(_) => f.write_str("Panic(..)"),
+        }
+    }
+}
+
+/// A handle to a spawned task, which resolves to the task's output.
+///
+/// Dropping the handle detaches the task, which keeps running.
+pub struct JoinHandle<T> {
+    output: oneshot::Receiver<Result<T, JoinError>>,
+    abort: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    /// Stops the task the next time it would be polled. If it hasn't
+    /// already finished, the handle resolves to `JoinError::Cancelled`.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The sender is only dropped without sending if the task itself was
+        // dropped before it finished
+        self.output
+            .poll_unpin(cx)
+            .map(|output| output.unwrap_or(Err(JoinError::Cancelled)))
+    }
+}
+
+// Wraps a future in a task that sends its output to a `JoinHandle`
+pub(crate) fn joinable<F>(future: F) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let (sender, output) = oneshot::channel();
+    let (abort, registration) = AbortHandle::new_pair();
+
+    let task = async move {
+        let result =
+            match Abortable::new(AssertUnwindSafe(future).catch_unwind(), registration).await {
+                Ok(Ok(output)) => Ok(output),
+                Ok(Err(payload)) => {
+                    report_panic(&*payload);
+                    Err(JoinError::Panic(payload))
+                }
+                Err(Aborted) => Err(JoinError::Cancelled),
+            };
+        // The handle may have been dropped, which is fine
+        let _ = sender.send(result);
+    };
+
+    (task, JoinHandle { output, abort })
+}
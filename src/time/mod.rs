@@ -0,0 +1,219 @@
+//! Timers kept by the runtime.
+//!
+//! Timers live in a timer wheel in the reactor rather than each being an
+//! io-uring operation. The reactor only keeps a single timeout in flight, for
+//! whichever timer is due first, so thousands of them are cheap.
+
+use crate::runtime::{reactor, try_reactor};
+use futures::stream::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+pub(crate) mod wheel;
+
+/// Waits until `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(deadline_after(duration))
+}
+
+/// Waits until `deadline`.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// A future that resolves once its deadline has passed. The timer isn't
+/// added to the runtime until the future is first polled.
+pub struct Sleep {
+    deadline: Instant,
+    // The reactor's id and the timer's key in its wheel
+    timer: Option<(u64, usize)>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// Changes the deadline, which can be earlier or later than the current
+    /// one, and even after the future has resolved.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+        if let Some((reactor_id, key)) = self.timer {
+            match try_reactor() {
+                Some(reactor) if reactor.id() == reactor_id => reactor.reset_timer(key, deadline),
+                // The timer went away with its runtime
+                _ => self.timer = None,
+            }
+        }
+    }
+
+    fn remove_timer(&mut self) {
+        let (reactor_id, key) = match self.timer.take() {
+            Some(timer) => timer,
+            None => return,
+        };
+        // If the runtime has already shut down, so has the timer
+        if let Some(reactor) = try_reactor() {
+            if reactor.id() == reactor_id {
+                reactor.remove_timer(key);
+            }
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.is_elapsed() {
+            self.remove_timer();
+            return Poll::Ready(());
+        }
+        let reactor = reactor();
+        match self.timer {
+            Some((reactor_id, key)) => {
+                assert_eq!(
+                    reactor.id(),
+                    reactor_id,
+                    "Sleep polled outside of the runtime it was created on"
+                );
+                reactor.set_timer_waker(key, cx.waker());
+            }
+            None => {
+                let key = reactor.insert_timer(self.deadline, cx.waker().clone());
+                self.timer = Some((reactor.id(), key));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.remove_timer();
+    }
+}
+
+/// Yields every `period`, starting straight away.
+///
+/// If ticks are missed because the task was busy, the next tick happens as
+/// soon as possible and the ones after it are a whole `period` later, rather
+/// than bursting to catch up.
+pub fn interval(period: Duration) -> Interval {
+    interval_at(Instant::now(), period)
+}
+
+/// Yields every `period`, starting at `start`.
+pub fn interval_at(start: Instant, period: Duration) -> Interval {
+    assert!(period > Duration::ZERO, "interval period must be non-zero");
+    Interval {
+        sleep: sleep_until(start),
+        period,
+    }
+}
+
+/// Returned by `interval`.
+pub struct Interval {
+    sleep: Sleep,
+    period: Duration,
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Waits for the next tick, returning the instant it was due.
+    pub async fn tick(&mut self) -> Instant {
+        futures::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context) -> Poll<Instant> {
+        futures::ready!(Pin::new(&mut self.sleep).poll(cx));
+        let due = self.sleep.deadline();
+        let now = Instant::now();
+        let next = match due.checked_add(self.period) {
+            Some(next) if next > now => next,
+            _ => deadline_after_from(now, self.period),
+        };
+        self.sleep.reset(next);
+        Poll::Ready(due)
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        self.poll_tick(cx).map(Some)
+    }
+}
+
+/// Runs `future`, giving up with `Elapsed` if it hasn't finished after
+/// `duration`. The future is dropped when it times out, so this shouldn't
+/// be used with operations that lend buffers to the kernel.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    timeout_at(deadline_after(duration), future)
+}
+
+/// Like `timeout`, but gives up at `deadline`.
+pub fn timeout_at<F: Future>(deadline: Instant, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep_until(deadline),
+    }
+}
+
+/// Returned by `timeout`.
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F> Timeout<F> {
+    pub fn into_inner(self) -> F {
+        self.future
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // Safety: the future is never moved out of a pinned `Timeout`, and
+        // `Sleep` is `Unpin`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep)
+            .poll(cx)
+            .map(|()| Err(Elapsed(())))
+    }
+}
+
+/// The error returned by `Timeout` when its deadline passes first.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("deadline has elapsed")]
+pub struct Elapsed(());
+
+// Durations too long to add to an `Instant` are close enough to forever
+fn deadline_after(duration: Duration) -> Instant {
+    deadline_after_from(Instant::now(), duration)
+}
+
+fn deadline_after_from(now: Instant, duration: Duration) -> Instant {
+    now.checked_add(duration)
+        .unwrap_or_else(|| now + Duration::from_secs(86400 * 365 * 30))
+}
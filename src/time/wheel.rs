@@ -0,0 +1,439 @@
+use slab::Slab;
+use std::convert::TryInto;
+use std::mem;
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+// Each level has 64 slots, and each slot spans as many ticks as a whole
+// level below it. With 1ms ticks, the levels span 64ms, ~4s, ~4m, ~4.7h,
+// ~12.4d and ~2.2y.
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+
+// Timers further away than this are placed as if they were this far away,
+// and placed again once that's reached
+const MAX_TICKS: u64 = 1 << (SLOT_BITS * LEVELS);
+
+/// A hierarchical timer wheel with a resolution of one millisecond.
+///
+/// A timer is placed on the level whose slots are as wide as how far away its
+/// deadline is, so inserting and removing timers takes constant time however
+/// many there are. When the wheel gets to a slot on a higher level, its
+/// timers are moved down to the levels below, until they're due.
+pub(crate) struct TimerWheel {
+    // The instant of tick 0
+    start: Instant,
+    // The tick up to which timers have been processed
+    elapsed: u64,
+    timers: Slab<Timer>,
+    levels: [Level; LEVELS],
+}
+
+struct Timer {
+    // The first tick at or after the timer's deadline
+    deadline: u64,
+    waker: Option<Waker>,
+    // The level and slot the timer is in, or None once it has fired
+    location: Option<(usize, usize)>,
+    // The neighbouring timers in the same slot
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+struct Level {
+    // A bit for each slot that has any timers in it
+    occupied: u64,
+    // The first timer in each slot, which links to the rest
+    slots: [Option<usize>; SLOTS],
+}
+
+impl TimerWheel {
+    pub fn new(start: Instant) -> TimerWheel {
+        TimerWheel {
+            start,
+            elapsed: 0,
+            timers: Slab::new(),
+            levels: Default::default(),
+        }
+    }
+
+    /// Adds a timer that wakes `waker` once `deadline` has passed, returning
+    /// the key that refers to it.
+    pub fn insert(&mut self, deadline: Instant, waker: Waker) -> usize {
+        let key = self.timers.insert(Timer {
+            deadline: self.deadline_tick(deadline),
+            waker: Some(waker),
+            location: None,
+            prev: None,
+            next: None,
+        });
+        self.schedule(key);
+        key
+    }
+
+    /// Moves a timer to a new deadline. A timer that has already fired is
+    /// scheduled again.
+    pub fn reset(&mut self, key: usize, deadline: Instant) {
+        self.unschedule(key);
+        self.timers[key].deadline = self.deadline_tick(deadline);
+        self.schedule(key);
+    }
+
+    /// Replaces the waker a timer wakes, returning the previous one. A timer
+    /// that has already fired is scheduled again, since whatever owns it is
+    /// still waiting on it.
+    pub fn set_waker(&mut self, key: usize, waker: &Waker) -> Option<Waker> {
+        if self.timers[key].location.is_none() {
+            self.schedule(key);
+        }
+        let timer = &mut self.timers[key];
+        match &timer.waker {
+            Some(previous) if previous.will_wake(waker) => None,
+            _ => timer.waker.replace(waker.clone()),
+        }
+    }
+
+    /// Removes a timer, returning its waker if it hasn't fired.
+    pub fn remove(&mut self, key: usize) -> Option<Waker> {
+        self.unschedule(key);
+        self.timers.remove(key).waker
+    }
+
+    /// When the next timer is due, if there are any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let (level, slot, start) = self.next_slot()?;
+        if level == 0 {
+            return Some(self.instant(start));
+        }
+        // The timers in a slot on a higher level don't have to be moved
+        // down until the first of them is due
+        let mut next = self.levels[level].slots[slot];
+        let mut deadline = u64::MAX;
+        while let Some(key) = next {
+            deadline = deadline.min(self.timers[key].deadline);
+            next = self.timers[key].next;
+        }
+        Some(self.instant(deadline.max(start)))
+    }
+
+    /// Fires the timers that are due at `now`, returning their wakers so
+    /// they can be woken once the wheel has been released.
+    pub fn process(&mut self, now: Instant) -> Vec<Waker> {
+        let now = self.now_tick(now);
+        let mut woken = Vec::new();
+        while let Some((level, slot, start)) = self.next_slot() {
+            if start > now {
+                break;
+            }
+            // Every level below this one is empty, so the wheel can skip
+            // ahead to the slot
+            self.elapsed = start;
+            let mut next = self.levels[level].take(slot);
+            while let Some(key) = next {
+                let timer = &mut self.timers[key];
+                next = timer.next.take();
+                timer.prev = None;
+                timer.location = None;
+                if timer.deadline <= self.elapsed {
+                    woken.extend(timer.waker.take());
+                } else {
+                    self.schedule(key);
+                }
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+        woken
+    }
+
+    fn schedule(&mut self, key: usize) {
+        let elapsed = self.elapsed;
+        // Timers that are already due fire on the next tick
+        let when = self.timers[key]
+            .deadline
+            .clamp(elapsed + 1, elapsed + MAX_TICKS - 1);
+        let level = level_for(elapsed, when);
+        let slot = ((when >> (level * SLOT_BITS)) % SLOTS as u64) as usize;
+
+        let head = self.levels[level].slots[slot].replace(key);
+        self.levels[level].occupied |= 1 << slot;
+        if let Some(head) = head {
+            self.timers[head].prev = Some(key);
+        }
+        let timer = &mut self.timers[key];
+        timer.location = Some((level, slot));
+        timer.prev = None;
+        timer.next = head;
+    }
+
+    fn unschedule(&mut self, key: usize) {
+        let timer = &mut self.timers[key];
+        let (level, slot) = match timer.location.take() {
+            Some(location) => location,
+            None => return,
+        };
+        let prev = timer.prev.take();
+        let next = timer.next.take();
+        match prev {
+            Some(prev) => self.timers[prev].next = next,
+            None => self.levels[level].slots[slot] = next,
+        }
+        match next {
+            Some(next) => self.timers[next].prev = prev,
+            None if prev.is_none() => self.levels[level].occupied &= !(1 << slot),
+            None => {}
+        }
+    }
+
+    // The first occupied slot on the lowest occupied level, which holds the
+    // earliest timers, and the tick it starts on
+    fn next_slot(&self) -> Option<(usize, usize, u64)> {
+        let (level, occupied) = self
+            .levels
+            .iter()
+            .map(|level| level.occupied)
+            .enumerate()
+            .find(|(_, occupied)| *occupied != 0)?;
+        let slot_range = 1u64 << (level * SLOT_BITS);
+        let level_range = slot_range << SLOT_BITS;
+        let current = ((self.elapsed / slot_range) % SLOTS as u64) as u32;
+        let slot = (occupied.rotate_right(current).trailing_zeros() + current) as usize % SLOTS;
+        let mut start = (self.elapsed & !(level_range - 1)) + slot as u64 * slot_range;
+        // Only the top level wraps around, for timers that are further away
+        // than its current rotation
+        if start <= self.elapsed {
+            start += level_range;
+        }
+        Some((level, slot, start))
+    }
+
+    // Rounded up, so that timers never fire early
+    fn deadline_tick(&self, deadline: Instant) -> u64 {
+        let nanos = deadline.saturating_duration_since(self.start).as_nanos();
+        nanos.div_ceil(1_000_000).try_into().unwrap_or(u64::MAX)
+    }
+
+    fn now_tick(&self, now: Instant) -> u64 {
+        let millis = now.saturating_duration_since(self.start).as_millis();
+        millis.try_into().unwrap_or(u64::MAX)
+    }
+
+    fn instant(&self, tick: u64) -> Instant {
+        self.start + Duration::from_millis(tick)
+    }
+}
+
+impl Default for Level {
+    fn default() -> Level {
+        Level {
+            occupied: 0,
+            slots: [None; SLOTS],
+        }
+    }
+}
+
+impl Level {
+    fn take(&mut self, slot: usize) -> Option<usize> {
+        self.occupied &= !(1 << slot);
+        mem::take(&mut self.slots[slot])
+    }
+}
+
+// The level is picked by the highest bit that differs between the current
+// tick and the timer's, so that the timer's slot is ahead of the wheel's
+// position on that level
+fn level_for(elapsed: u64, when: u64) -> usize {
+    let masked = ((elapsed ^ when) | (SLOTS as u64 - 1)).min(MAX_TICKS - 1);
+    let significant = 63 - masked.leading_zeros() as usize;
+    significant / SLOT_BITS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::{waker, ArcWake};
+    use std::sync::{Arc, Mutex};
+
+    // Records the names of the timers whose wakers have been woken
+    type Fired = Arc<Mutex<Vec<&'static str>>>;
+
+    struct Named(&'static str, Fired);
+
+    impl ArcWake for Named {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.1.lock().unwrap().push(arc_self.0);
+        }
+    }
+
+    // A wheel driven by synthetic instants, in milliseconds since its start
+    struct Harness {
+        start: Instant,
+        wheel: TimerWheel,
+        fired: Fired,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let start = Instant::now();
+            Harness {
+                start,
+                wheel: TimerWheel::new(start),
+                fired: Arc::default(),
+            }
+        }
+
+        fn at(&self, millis: u64) -> Instant {
+            self.start + Duration::from_millis(millis)
+        }
+
+        fn insert(&mut self, name: &'static str, millis: u64) -> usize {
+            let waker = waker(Arc::new(Named(name, self.fired.clone())));
+            self.wheel.insert(self.at(millis), waker)
+        }
+
+        // Processes the wheel up to `millis`, returning the timers that fired
+        fn advance(&mut self, millis: u64) -> Vec<&'static str> {
+            for waker in self.wheel.process(self.at(millis)) {
+                waker.wake();
+            }
+            mem::take(&mut *self.fired.lock().unwrap())
+        }
+
+        fn level(&self, key: usize) -> Option<usize> {
+            self.wheel.timers[key].location.map(|(level, _)| level)
+        }
+    }
+
+    #[test]
+    fn places_timers_on_the_level_for_their_distance() {
+        let mut harness = Harness::new();
+        let timers = [
+            (harness.insert("1ms", 1), 0),
+            (harness.insert("63ms", 63), 0),
+            (harness.insert("64ms", 64), 1),
+            (harness.insert("4095ms", 4095), 1),
+            (harness.insert("4096ms", 4096), 2),
+            (harness.insert("2^18ms", 1 << 18), 3),
+            (harness.insert("2^30ms", 1 << 30), 5),
+        ];
+        for (key, level) in timers {
+            assert_eq!(harness.level(key), Some(level));
+        }
+    }
+
+    #[test]
+    fn fires_timers_once_their_deadline_has_passed() {
+        let mut harness = Harness::new();
+        harness.insert("10ms", 10);
+        harness.insert("100ms", 100);
+        assert_eq!(harness.wheel.next_deadline(), Some(harness.at(10)));
+        assert!(harness.advance(9).is_empty());
+        assert_eq!(harness.advance(10), ["10ms"]);
+        assert_eq!(harness.wheel.next_deadline(), Some(harness.at(100)));
+        assert!(harness.advance(99).is_empty());
+        assert_eq!(harness.advance(100), ["100ms"]);
+        assert_eq!(harness.wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn rounds_deadlines_up_to_the_next_tick() {
+        let mut harness = Harness::new();
+        let deadline = harness.at(5) + Duration::from_micros(1);
+        let fired = harness.fired.clone();
+        harness
+            .wheel
+            .insert(deadline, waker(Arc::new(Named("5.001ms", fired))));
+        assert!(harness.advance(5).is_empty());
+        assert_eq!(harness.advance(6), ["5.001ms"]);
+    }
+
+    #[test]
+    fn cascades_timers_down_to_lower_levels() {
+        let mut harness = Harness::new();
+        let key = harness.insert("5000ms", 5000);
+        assert_eq!(harness.level(key), Some(2));
+        // The level 2 slot starting at 4096 and the level 1 slot starting
+        // at 4992 have both been reached, moving the timer down each time
+        assert!(harness.advance(4999).is_empty());
+        assert_eq!(harness.level(key), Some(0));
+        assert_eq!(harness.advance(5000), ["5000ms"]);
+        assert_eq!(harness.level(key), None);
+    }
+
+    #[test]
+    fn fires_timers_across_rotations_of_a_level() {
+        let mut harness = Harness::new();
+        assert!(harness.advance(60).is_empty());
+        // Each of these is past the end of level 0's current rotation
+        harness.insert("70ms", 70);
+        harness.insert("127ms", 127);
+        harness.insert("130ms", 130);
+        harness.insert("4100ms", 4100);
+        assert!(harness.advance(69).is_empty());
+        assert_eq!(harness.advance(70), ["70ms"]);
+        assert!(harness.advance(126).is_empty());
+        assert_eq!(harness.advance(130), ["127ms", "130ms"]);
+        assert!(harness.advance(4099).is_empty());
+        assert_eq!(harness.advance(4100), ["4100ms"]);
+    }
+
+    #[test]
+    fn places_timers_beyond_the_top_level_again() {
+        let mut harness = Harness::new();
+        let key = harness.insert("far", MAX_TICKS + 10);
+        assert_eq!(harness.level(key), Some(LEVELS - 1));
+        // The timer is reached once the top level wraps around, and is
+        // placed again for the rest of the way
+        assert!(harness.advance(MAX_TICKS - 1).is_empty());
+        assert!(harness.level(key).is_some());
+        assert!(harness.advance(MAX_TICKS + 9).is_empty());
+        assert_eq!(harness.advance(MAX_TICKS + 10), ["far"]);
+    }
+
+    #[test]
+    fn fires_due_timers_after_skipping_ahead() {
+        let mut harness = Harness::new();
+        harness.insert("3ms", 3);
+        harness.insert("200ms", 200);
+        harness.insert("70000ms", 70_000);
+        assert_eq!(harness.advance(100_000), ["3ms", "200ms", "70000ms"]);
+    }
+
+    #[test]
+    fn removes_timers_before_they_fire() {
+        let mut harness = Harness::new();
+        let key = harness.insert("10ms", 10);
+        harness.insert("20ms", 20);
+        assert!(harness.wheel.remove(key).is_some());
+        assert_eq!(harness.advance(20), ["20ms"]);
+    }
+
+    #[test]
+    fn removes_timers_that_have_fired() {
+        let mut harness = Harness::new();
+        let fired = harness.insert("10ms", 10);
+        let other = harness.insert("10ms too", 10);
+        assert_eq!(harness.advance(10), ["10ms too", "10ms"]);
+        // Its waker has been handed out already, and it's no longer in a slot
+        assert!(harness.wheel.remove(fired).is_none());
+        assert!(harness.wheel.remove(other).is_none());
+        assert_eq!(harness.wheel.next_deadline(), None);
+
+        // The keys are reused without disturbing the new timers
+        harness.insert("30ms", 30);
+        harness.insert("40ms", 40);
+        assert_eq!(harness.advance(40), ["30ms", "40ms"]);
+    }
+
+    #[test]
+    fn schedules_fired_timers_again_when_reset() {
+        let mut harness = Harness::new();
+        let key = harness.insert("10ms", 10);
+        assert_eq!(harness.advance(10), ["10ms"]);
+        harness.wheel.reset(key, harness.at(50));
+        let waker = waker(Arc::new(Named("reset", harness.fired.clone())));
+        harness.wheel.set_waker(key, &waker);
+        assert!(harness.advance(49).is_empty());
+        assert_eq!(harness.advance(50), ["reset"]);
+    }
+}
@@ -0,0 +1,109 @@
+//! Adapter for running `tower::Service`s on the `HttpServer`.
+//!
+//! Requires the `tower` cargo feature.
+
+use crate::handler::{ConnectionHandler, Handler};
+use futures::future::{poll_fn, FutureExt, LocalBoxFuture};
+use http::{Request, Response, StatusCode};
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+use std::sync::Mutex;
+use tower_service::Service;
+use tracing::error;
+
+/// Wraps a `tower::Service` so it can be used as a `Handler`.
+///
+/// Services take ownership of the request, so the request body is copied
+/// out of the connection's buffer. The service is cloned for each
+/// connection, and as is the convention for tower services, the clone must
+/// report that it is ready (through `poll_ready`) before each request on the
+/// connection is passed to it. Inside a `Router`, which picks a handler for
+/// each request, it's cloned for each request instead. Errors from the
+/// service are logged and turned into a 500 response.
+///
+/// ```ignore
+/// let service = ServiceBuilder::new()
+///     .concurrency_limit(64)
+///     .service(my_service);
+/// server.serve(ServiceHandler::new(service)).await;
+/// ```
+pub struct ServiceHandler<S> {
+    // Handlers are shared between threads, but services don't need to be
+    // Sync, so the service is only ever touched (to clone it) under a lock
+    service: Mutex<S>,
+}
+
+impl<S> ServiceHandler<S> {
+    pub fn new(service: S) -> ServiceHandler<S> {
+        ServiceHandler {
+            service: Mutex::new(service),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.service.into_inner().unwrap()
+    }
+}
+
+impl<S: Clone> ServiceHandler<S> {
+    // Requests that overlap each other are never passed to the same clone,
+    // so it's only borrowed while it's being polled
+    fn clone_service(&self) -> Rc<RefCell<S>> {
+        Rc::new(RefCell::new(self.service.lock().unwrap().clone()))
+    }
+}
+
+impl<S> Handler for ServiceHandler<S>
+where
+    S: Service<Request<Vec<u8>>, Response = Response<Vec<u8>>> + Clone + 'static + Send,
+    S::Future: 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Future = LocalBoxFuture<'static, Response<Vec<u8>>>;
+
+    fn call(&self, request: Request<&[u8]>) -> Self::Future {
+        call_service(self.clone_service(), request)
+    }
+
+    fn connection_handler(&self) -> Option<ConnectionHandler> {
+        let service = self.clone_service();
+        Some(Box::new(move |request| {
+            call_service(service.clone(), request)
+        }))
+    }
+}
+
+fn call_service<S>(
+    service: Rc<RefCell<S>>,
+    request: Request<&[u8]>,
+) -> LocalBoxFuture<'static, Response<Vec<u8>>>
+where
+    S: Service<Request<Vec<u8>>, Response = Response<Vec<u8>>> + 'static,
+    S::Future: 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let request = request.map(|body| body.to_vec());
+    async move {
+        if let Err(err) = poll_fn(|cx| service.borrow_mut().poll_ready(cx)).await {
+            error!("Service failed to become ready: {}", err.into());
+            return internal_server_error();
+        }
+        let response = service.borrow_mut().call(request);
+        match response.await {
+            Ok(response) => response,
+            Err(err) => {
+                error!("Service returned an error: {}", err.into());
+                internal_server_error()
+            }
+        }
+    }
+    .boxed_local()
+}
+
+fn internal_server_error() -> Response<Vec<u8>> {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body("Internal Server Error".as_bytes().to_vec())
+        .unwrap()
+}